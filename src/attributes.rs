@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use color_eyre::{Result, eyre::eyre};
+
+use super::{Config, DiagramOutputFormat};
+
+/// Per-diagram settings parsed from the info string of a fenced code block,
+/// i.e. ```` ```plantuml {format=png, theme=amiga, alt="Login flow", width=600} ````
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Attributes {
+    pub output_format: Option<DiagramOutputFormat>,
+    pub alt: Option<String>,
+    /// a longer description of the diagram for screen readers
+    pub description: Option<String>,
+    pub width: Option<String>,
+    pub height: Option<String>,
+    /// a file to read the diagram source from, relative to the chapter
+    pub src: Option<String>,
+    /// shown under the diagram, and makes it a numbered figure
+    pub caption: Option<String>,
+    /// the anchor `{{#figref id}}` links to, also makes the diagram a
+    /// numbered figure
+    pub id: Option<String>,
+    pub show_source: Option<bool>,
+    /// turns rendering a second copy for dark themes on or off for this block
+    pub dark_mode: Option<bool>,
+    /// any attribute that isn't one of the above is passed on to Kroki as a
+    /// diagram option
+    pub diagram_options: HashMap<String, String>,
+}
+
+impl Attributes {
+    /// Returns a copy of the global config with this block's overrides applied
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(output_format) = self.output_format {
+            config.output_format = output_format;
+        }
+        if let Some(show_source) = self.show_source {
+            config.show_source = show_source;
+        }
+        if let Some(dark_mode) = self.dark_mode {
+            config.dark_mode = dark_mode;
+        }
+        for (key, value) in &self.diagram_options {
+            config.diagram_options.insert(key.clone(), value.clone());
+        }
+        config
+    }
+
+    /// Diagrams with a caption or id are shown as (numbered) figures
+    pub fn is_figure(&self) -> bool {
+        self.caption.is_some() || self.id.is_some()
+    }
+}
+
+/// Splits a code block info string into the language and whatever follows it.
+/// The language ends at the first whitespace or `{`
+pub(crate) fn split_info_string(info: &str) -> (&str, &str) {
+    let info = info.trim();
+    let lang_end = info
+        .find(|c: char| c.is_whitespace() || c == '{')
+        .unwrap_or(info.len());
+    let (lang, rest) = info.split_at(lang_end);
+    (lang, rest.trim())
+}
+
+/// Parses the `{key=value, ...}` attribute list that follows the language of
+/// a diagram code block
+pub(crate) fn parse_attributes(input: &str) -> Result<Attributes> {
+    let mut attributes = Attributes::default();
+    if input.is_empty() {
+        return Ok(attributes);
+    }
+
+    let inner = input
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .ok_or_else(|| eyre!("Expected attributes wrapped in {{...}}, got: {input}"))?;
+
+    for (key, value) in parse_key_values(inner)? {
+        match key.as_str() {
+            "format" => attributes.output_format = Some(value.parse()?),
+            "alt" => attributes.alt = Some(value),
+            "description" => attributes.description = Some(value),
+            "width" => attributes.width = Some(value),
+            "height" => attributes.height = Some(value),
+            "src" => attributes.src = Some(value),
+            "caption" => attributes.caption = Some(value),
+            "id" => attributes.id = Some(value),
+            "show_source" => {
+                attributes.show_source = Some(value.parse().map_err(|_| {
+                    eyre!("Expected `show_source` to be true or false, got: {value}")
+                })?)
+            }
+            "dark_mode" => {
+                attributes.dark_mode =
+                    Some(value.parse().map_err(|_| {
+                        eyre!("Expected `dark_mode` to be true or false, got: {value}")
+                    })?)
+            }
+            _ => {
+                attributes.diagram_options.insert(key, value);
+            }
+        }
+    }
+
+    Ok(attributes)
+}
+
+fn parse_key_values(input: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && *c != ',' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('=') {
+            return Err(eyre!("Expected `{key}=<value>` in diagram attributes"));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        match chars.peek().copied() {
+            Some(quote @ ('"' | '\'')) => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            if let Some(c) = chars.next() {
+                                value.push(c);
+                            }
+                        }
+                        Some(c) if c == quote => break,
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(eyre!(
+                                "Unterminated quoted value for `{key}` in diagram attributes"
+                            ));
+                        }
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| *c != ',' && !c.is_whitespace()) {
+                    value.push(c);
+                }
+            }
+        }
+
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_info_string(info: &str) -> Result<(&str, Attributes)> {
+        let (lang, rest) = split_info_string(info);
+        Ok((lang, parse_attributes(rest)?))
+    }
+
+    #[test]
+    fn splits_language_from_attributes() {
+        assert_eq!(split_info_string("mermaid"), ("mermaid", ""));
+        assert_eq!(split_info_string("mermaidfoo"), ("mermaidfoo", ""));
+        assert_eq!(
+            split_info_string(" plantuml  {theme=amiga} "),
+            ("plantuml", "{theme=amiga}")
+        );
+        assert_eq!(
+            split_info_string("mermaid{theme=dark}"),
+            ("mermaid", "{theme=dark}")
+        );
+    }
+
+    #[test]
+    fn parses_attributes() {
+        let (lang, attributes) =
+            parse_info_string(r#"plantuml {format=png, theme=amiga, alt="Login flow", width=600}"#)
+                .unwrap();
+        assert_eq!(lang, "plantuml");
+        assert_eq!(attributes.output_format, Some(DiagramOutputFormat::Png));
+        assert_eq!(attributes.alt.as_deref(), Some("Login flow"));
+        assert_eq!(attributes.width.as_deref(), Some("600"));
+        assert_eq!(attributes.height, None);
+        assert_eq!(
+            attributes.diagram_options.get("theme").map(String::as_str),
+            Some("amiga")
+        );
+    }
+
+    #[test]
+    fn parses_quoted_values_with_escapes() {
+        let (lang, attributes) =
+            parse_info_string(r#"mermaid{alt='it\'s a "flow"' look=handDrawn}"#).unwrap();
+        assert_eq!(lang, "mermaid");
+        assert_eq!(attributes.alt.as_deref(), Some(r#"it's a "flow""#));
+        assert_eq!(
+            attributes.diagram_options.get("look").map(String::as_str),
+            Some("handDrawn")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_attributes() {
+        assert!(parse_attributes("theme=dark").is_err());
+        assert!(parse_attributes("{theme}").is_err());
+        assert!(parse_attributes(r#"{alt="oops}"#).is_err());
+        assert!(parse_attributes("{format=gif}").is_err());
+    }
+}
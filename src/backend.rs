@@ -0,0 +1,632 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::Path,
+    process::Stdio,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use mime::Mime;
+use serde_json::json;
+use ureq::{
+    Agent, Proxy,
+    http::StatusCode,
+    tls::{Certificate, ClientCert, PemItem, PrivateKey, RootCerts, TlsConfig, parse_pem},
+};
+
+use super::{Config, DiagramOutputFormat, DiagramType};
+
+/// Everything a backend needs to know to render a single diagram
+pub(crate) struct RenderRequest<'a> {
+    pub source: &'a str,
+    pub diagram_type: &'a DiagramType,
+    pub output_format: DiagramOutputFormat,
+    pub diagram_options: &'a BTreeMap<String, String>,
+}
+
+/// Something that can turn diagram source into rendered bytes
+pub(crate) trait Backend: Send + Sync {
+    fn render(&self, request: &RenderRequest) -> Result<Vec<u8>>;
+
+    /// A URL that renders the diagram when it is fetched, for backends that
+    /// pages can link to instead of rendering at build time
+    fn link(&self, _request: &RenderRequest) -> Option<String> {
+        None
+    }
+
+    /// Checks that the diagram at a URL returned by `link` renders
+    fn check_link(&self, _url: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Identifies this backend and how it is set up, so that switching
+    /// backends invalidates cached diagrams
+    fn cache_key(&self) -> String;
+}
+
+/// The backends that can be selected per diagram type in `book.toml`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum BackendKind {
+    Kroki,
+    Mmdc,
+    PlantUml,
+    Dot,
+    D2,
+    Ditaa,
+}
+
+impl BackendKind {
+    /// The command that is run for local backends, unless overridden by the
+    /// `commands` table
+    fn default_command(&self) -> &'static [&'static str] {
+        match self {
+            BackendKind::Kroki => &[],
+            BackendKind::Mmdc => &["mmdc"],
+            BackendKind::PlantUml => &["plantuml"],
+            BackendKind::Dot => &["dot"],
+            BackendKind::D2 => &["d2"],
+            BackendKind::Ditaa => &["ditaa"],
+        }
+    }
+
+    fn supports(&self, diagram_type: &DiagramType) -> bool {
+        match self {
+            BackendKind::Kroki => true,
+            BackendKind::Mmdc => *diagram_type == DiagramType::Mermaid,
+            BackendKind::PlantUml => *diagram_type == DiagramType::PlantUml,
+            BackendKind::Dot => *diagram_type == DiagramType::Graphviz,
+            BackendKind::D2 => *diagram_type == DiagramType::D2,
+            BackendKind::Ditaa => *diagram_type == DiagramType::Ditaa,
+        }
+    }
+
+    /// Whether the tool behind the backend can produce `output_format`
+    fn supports_format(&self, output_format: DiagramOutputFormat) -> bool {
+        use DiagramOutputFormat::*;
+        match self {
+            BackendKind::Kroki => true,
+            BackendKind::Mmdc | BackendKind::D2 => matches!(output_format, Png | Svg | Pdf),
+            BackendKind::PlantUml => matches!(output_format, Png | Svg | Pdf | Txt),
+            BackendKind::Dot => matches!(output_format, Png | Svg | Pdf | Jpeg | Webp),
+            BackendKind::Ditaa => matches!(output_format, Png | Svg),
+        }
+    }
+}
+
+/// The longest we'll wait between two attempts, whatever the server asks for
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How failed requests to Kroki are retried
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    /// how many times a request is retried after the first attempt
+    pub retries: u32,
+    /// the delay before the first retry, doubled for each one after that
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter: a random delay between half and all
+    /// of `backoff * 2^attempt`, so that concurrent requests that failed
+    /// together don't all retry at the same moment
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+        // we don't need a good random number, just one that differs between
+        // threads and attempts
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let jitter = (nanos % 1000) as f64 / 1000.0;
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+/// Renders diagrams by sending them to a Kroki service
+pub(crate) struct Kroki {
+    agent: Agent,
+    url: String,
+    /// sent with every request, i.e. for authentication
+    headers: Vec<(String, String)>,
+    retry: RetryPolicy,
+    offline: bool,
+}
+
+impl Kroki {
+    pub fn new(agent: Agent, url: String, config: &Config) -> Self {
+        Kroki {
+            agent,
+            url,
+            headers: config.kroki_headers.clone(),
+            retry: config.kroki_retry,
+            offline: config.offline,
+        }
+    }
+}
+
+/// Builds the HTTP client used for Kroki, with the proxy and TLS settings
+/// from the config
+pub(crate) fn kroki_agent(config: &Config) -> Result<Agent> {
+    let mut agent_config = Agent::config_builder()
+        .timeout_global(config.kroki_timeout)
+        // handle error statuses ourselves so we can report Kroki's message
+        .http_status_as_error(false);
+    if let Some(proxy) = &config.kroki_proxy {
+        let proxy = Proxy::new(proxy).wrap_err_with(|| format!("Invalid Kroki proxy: {proxy}"))?;
+        agent_config = agent_config.proxy(Some(proxy));
+    }
+
+    let mut tls_config = TlsConfig::builder();
+    if let Some(ca_cert) = &config.kroki_ca_cert {
+        let (certs, _) = read_pem(ca_cert)?;
+        if certs.is_empty() {
+            return Err(eyre!("No certificates found in {}", ca_cert.display()));
+        }
+        tls_config = tls_config.root_certs(RootCerts::new_with_certs(&certs));
+    }
+    if let Some(client_cert) = &config.kroki_client_cert {
+        let (certs, key) = read_pem(client_cert)?;
+        // the key can be kept in the same file as the certificate
+        let key = match &config.kroki_client_key {
+            Some(client_key) => read_pem(client_key)?.1,
+            None => key,
+        };
+        let key = key.ok_or_else(|| {
+            eyre!(
+                "No private key found for the client certificate {}",
+                client_cert.display()
+            )
+        })?;
+        tls_config = tls_config.client_cert(Some(ClientCert::new_with_certs(&certs, key)));
+    }
+
+    Ok(agent_config.tls_config(tls_config.build()).build().into())
+}
+
+/// Reads the certificates and the first private key from a PEM file
+fn read_pem(path: &Path) -> Result<(Vec<Certificate<'static>>, Option<PrivateKey<'static>>)> {
+    let pem = std::fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let mut certs = Vec::new();
+    let mut key = None;
+    for item in parse_pem(&pem) {
+        match item.wrap_err_with(|| format!("Failed to parse {}", path.display()))? {
+            PemItem::Certificate(cert) => certs.push(cert),
+            PemItem::PrivateKey(private_key) => {
+                key.get_or_insert(private_key);
+            }
+            _ => {}
+        }
+    }
+    Ok((certs, key))
+}
+
+/// Statuses that mean the service is (hopefully only briefly) unavailable
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn is_transient_error(error: &ureq::Error) -> bool {
+    matches!(
+        error,
+        ureq::Error::Io(_) | ureq::Error::Timeout(_) | ureq::Error::ConnectionFailed
+    )
+}
+
+/// Reads a `Retry-After` header given in seconds (the HTTP date form isn't
+/// supported and falls back to the normal backoff)
+fn retry_after(response: &ureq::http::Response<ureq::Body>) -> Option<Duration> {
+    let seconds: u64 = response
+        .headers()
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_DELAY))
+}
+
+impl Backend for Kroki {
+    fn render(&self, request: &RenderRequest) -> Result<Vec<u8>> {
+        let kroki_url = &self.url;
+        if self.offline {
+            return Err(eyre!(
+                "Diagram is not in the cache and offline mode is enabled, so it can't be sent to Kroki"
+            ));
+        }
+
+        let req = json!({
+            "diagram_source": request.source,
+            "diagram_type": request.diagram_type.to_string(),
+            "output_format": request.output_format.to_string(),
+            "diagram_options": request.diagram_options,
+        });
+
+        let mut response = self.send(|| {
+            self.headers
+                .iter()
+                .fold(self.agent.post(kroki_url), |request, (name, value)| {
+                    request.header(name, value)
+                })
+                .header("Content-Type", "application/json")
+                .send_json(&req)
+        })?;
+
+        if let Some(mime_type) = response.headers().get("Content-Type") {
+            let mime_type = mime_type
+                .to_str()
+                .wrap_err("Failed to convert response mime type to string")?;
+            let mime_type: Mime = mime_type.parse().wrap_err_with(|| {
+                format!("Failed to parse response mime type as MIME type: {mime_type}",)
+            })?;
+
+            // ignore parameters like the charset of text
+            let expected = request.output_format.mime_type();
+            if mime_type.essence_str() != expected.essence_str() {
+                return Err(eyre!(
+                    "Unexpected response mime type from Kroki service: {mime_type} (expected {essence} for {output_format})",
+                    essence = expected.essence_str(),
+                    output_format = request.output_format
+                ));
+            }
+        }
+
+        response
+            .body_mut()
+            .read_to_vec()
+            .wrap_err("Failed to read diagram response")
+    }
+
+    /// Kroki renders diagrams from `GET /<type>/<format>/<source>` too, with
+    /// the source deflated and base64url encoded and the diagram options in
+    /// the query string
+    fn link(&self, request: &RenderRequest) -> Option<String> {
+        use base64::prelude::*;
+        use flate2::{Compression, write::ZlibEncoder};
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(request.source.as_bytes()).ok()?;
+        let encoded = BASE64_URL_SAFE.encode(encoder.finish().ok()?);
+
+        let mut url = format!(
+            "{}/{}/{}/{encoded}",
+            self.url.trim_end_matches('/'),
+            request.diagram_type,
+            request.output_format
+        );
+        let query = request
+            .diagram_options
+            .iter()
+            .map(|(key, value)| format!("{}={}", url_encode(key), url_encode(value)))
+            .collect::<Vec<_>>();
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        Some(url)
+    }
+
+    fn check_link(&self, url: &str) -> Result<()> {
+        // a link is all the page needs, so offline builds can skip the check
+        if self.offline {
+            return Ok(());
+        }
+        self.send(|| {
+            self.headers
+                .iter()
+                .fold(self.agent.get(url), |request, (name, value)| {
+                    request.header(name, value)
+                })
+                .call()
+        })
+        .map(|_| ())
+    }
+
+    fn cache_key(&self) -> String {
+        // offline mode and retries don't change what gets rendered
+        format!("kroki {}", self.url)
+    }
+}
+
+impl Kroki {
+    /// Sends a request to Kroki, retrying it if it fails with a connection
+    /// error, timeout or transient status, and returns the successful response
+    fn send(
+        &self,
+        request: impl Fn() -> Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+    ) -> Result<ureq::http::Response<ureq::Body>> {
+        let kroki_url = &self.url;
+        let mut attempt = 0;
+        loop {
+            let result = request();
+            let can_retry = attempt < self.retry.retries;
+
+            let delay = match result {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) if can_retry && is_transient_status(response.status()) => {
+                    retry_after(&response).unwrap_or_else(|| self.retry.delay(attempt))
+                }
+                Ok(mut response) => {
+                    let status = response.status();
+                    let message = response.body_mut().read_to_string().unwrap_or_default();
+                    return Err(eyre!(
+                        "Kroki service at {kroki_url} responded with {status}: {message}",
+                        message = message.trim()
+                    ));
+                }
+                Err(e) if can_retry && is_transient_error(&e) => self.retry.delay(attempt),
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| {
+                        format!("Failed to send diagram to Kroki service at {kroki_url}")
+                    });
+                }
+            };
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Renders diagrams by running a locally installed command line tool
+pub(crate) struct Command {
+    kind: BackendKind,
+    command: Vec<String>,
+}
+
+impl Command {
+    pub fn new(kind: BackendKind, command: Option<&Vec<String>>) -> Self {
+        let command = match command {
+            Some(command) => command.clone(),
+            None => kind
+                .default_command()
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+        Command { kind, command }
+    }
+}
+
+impl Backend for Command {
+    fn render(&self, request: &RenderRequest) -> Result<Vec<u8>> {
+        let (program, base_args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| eyre!("Empty command configured for the {} backend", self.kind))?;
+
+        if !self.kind.supports_format(request.output_format) {
+            return Err(eyre!(
+                "The {} backend can't render {} diagrams as {}",
+                self.kind,
+                request.diagram_type,
+                request.output_format
+            ));
+        }
+
+        // tools that can't read from stdin / write to stdout get files in a
+        // scratch directory instead
+        let dir = tempfile::tempdir().wrap_err("Failed to create a scratch directory")?;
+        let format = request.output_format.to_string();
+        let input = dir.path().join("diagram.in");
+        let output = dir.path().join(format!("diagram.{format}"));
+        let options = request.diagram_options;
+        let input_str = input.to_string_lossy().to_string();
+        let output_str = output.to_string_lossy().to_string();
+
+        let mut args: Vec<String> = Vec::new();
+        let mut use_stdio = false;
+        match self.kind {
+            BackendKind::Kroki => unreachable!("kroki is not a command backend"),
+            BackendKind::Mmdc => {
+                args.extend(["-q".into(), "-i".into(), input_str, "-o".into(), output_str]);
+                args.extend(["-e".into(), format.clone()]);
+                if let Some(theme) = options.get("theme") {
+                    args.extend(["-t".into(), theme.clone()]);
+                }
+                if options.get("html-labels").map(String::as_str) == Some("false") {
+                    let config_path = dir.path().join("config.json");
+                    std::fs::write(
+                        &config_path,
+                        r#"{"htmlLabels": false, "flowchart": {"htmlLabels": false}}"#,
+                    )
+                    .wrap_err("Failed to write mermaid config file")?;
+                    args.extend(["-c".into(), config_path.to_string_lossy().to_string()]);
+                }
+            }
+            BackendKind::PlantUml => {
+                args.extend([
+                    "-pipe".into(),
+                    format!("-t{format}"),
+                    "-charset".into(),
+                    "UTF-8".into(),
+                ]);
+                use_stdio = true;
+            }
+            BackendKind::Dot => {
+                args.push(format!("-T{format}"));
+                use_stdio = true;
+            }
+            BackendKind::D2 => {
+                for key in ["theme", "layout"] {
+                    if let Some(value) = options.get(key) {
+                        args.push(format!("--{key}={value}"));
+                    }
+                }
+                if options.get("sketch").map(String::as_str) == Some("true") {
+                    args.push("--sketch".into());
+                }
+                args.extend([input_str, output_str]);
+            }
+            BackendKind::Ditaa => {
+                if request.output_format == DiagramOutputFormat::Svg {
+                    args.push("--svg".into());
+                }
+                for (key, value) in options {
+                    match key.as_str() {
+                        "scale" => args.extend(["--scale".into(), value.clone()]),
+                        _ if value == "true" => args.push(format!("--{key}")),
+                        _ => {}
+                    }
+                }
+                args.extend([input_str, output_str]);
+            }
+        }
+
+        if !use_stdio {
+            std::fs::write(&input, request.source)
+                .wrap_err("Failed to write diagram source to scratch file")?;
+        }
+
+        let mut child = std::process::Command::new(program)
+            .args(base_args)
+            .args(&args)
+            .stdin(if use_stdio {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to run `{program}` for the {} backend", self.kind))?;
+        // feed stdin from another thread so a tool that starts writing before
+        // it has read all of its input can't deadlock us
+        let writer = child.stdin.take().map(|mut stdin| {
+            let source = request.source.to_string();
+            std::thread::spawn(move || stdin.write_all(source.as_bytes()))
+        });
+        let result = child
+            .wait_with_output()
+            .wrap_err_with(|| format!("Failed to wait for `{program}`"))?;
+
+        if !result.status.success() {
+            return Err(eyre!(
+                "`{program}` exited with {status}: {stderr}",
+                status = result.status,
+                stderr = String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+
+        if let Some(writer) = writer {
+            writer
+                .join()
+                .map_err(|_| eyre!("Failed to write diagram source to `{program}`"))?
+                .wrap_err_with(|| format!("Failed to write diagram source to `{program}`"))?;
+        }
+
+        if use_stdio {
+            Ok(result.stdout)
+        } else {
+            std::fs::read(&output).wrap_err_with(|| {
+                format!(
+                    "`{program}` did not produce an output file at {}",
+                    output.display()
+                )
+            })
+        }
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{} {}", self.kind, self.command.join(" "))
+    }
+}
+
+/// The backends configured for a book, keyed by diagram type
+pub(crate) struct Backends {
+    kroki: Kroki,
+    /// backends for diagram types that use a local tool or their own Kroki
+    /// server
+    by_type: HashMap<DiagramType, Box<dyn Backend>>,
+}
+
+impl Backends {
+    pub fn new(config: &Config, agent: Agent) -> Result<Self> {
+        let mut by_type: HashMap<DiagramType, Box<dyn Backend>> = HashMap::new();
+        for (diagram_type, type_config) in &config.types {
+            let kind = type_config.backend.unwrap_or(BackendKind::Kroki);
+            if kind == BackendKind::Kroki {
+                if let Some(url) = &type_config.kroki_url {
+                    by_type.insert(
+                        diagram_type.clone(),
+                        Box::new(Kroki::new(agent.clone(), url.clone(), config)),
+                    );
+                }
+                continue;
+            }
+            if !kind.supports(diagram_type) {
+                return Err(eyre!(
+                    "The {kind} backend can't render {diagram_type} diagrams"
+                ));
+            }
+            let command = type_config
+                .command
+                .as_ref()
+                .or_else(|| config.commands.get(&kind));
+            by_type.insert(diagram_type.clone(), Box::new(Command::new(kind, command)));
+        }
+
+        Ok(Backends {
+            kroki: Kroki::new(agent, config.kroki_url.clone(), config),
+            by_type,
+        })
+    }
+
+    pub fn get(&self, diagram_type: &DiagramType) -> &dyn Backend {
+        match self.by_type.get(diagram_type) {
+            Some(backend) => backend.as_ref(),
+            None => &self.kroki,
+        }
+    }
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kroki" => Ok(BackendKind::Kroki),
+            "mmdc" => Ok(BackendKind::Mmdc),
+            "plantuml" => Ok(BackendKind::PlantUml),
+            "dot" => Ok(BackendKind::Dot),
+            "d2" => Ok(BackendKind::D2),
+            "ditaa" => Ok(BackendKind::Ditaa),
+            _ => Err(eyre!(
+                "Invalid backend: {s}, expected one of 'kroki', 'mmdc', 'plantuml', 'dot', 'd2' or 'ditaa'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendKind::Kroki => write!(f, "kroki"),
+            BackendKind::Mmdc => write!(f, "mmdc"),
+            BackendKind::PlantUml => write!(f, "plantuml"),
+            BackendKind::Dot => write!(f, "dot"),
+            BackendKind::D2 => write!(f, "d2"),
+            BackendKind::Ditaa => write!(f, "ditaa"),
+        }
+    }
+}
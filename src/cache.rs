@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use serde::{Deserialize, Serialize};
+
+/// Bump this whenever the way diagrams are rendered or stored changes so that
+/// entries written by older versions are never served again
+pub(crate) const CACHE_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The book's cache of rendered diagrams. Entries are stored as plain files
+/// under `<dir>/v<CACHE_VERSION>/` alongside a manifest that records when each
+/// was last used so that old entries can be pruned.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    manifest: Mutex<Manifest>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    /// seconds since the unix epoch
+    last_used: u64,
+    /// not persisted, set when the entry is used during the current run so
+    /// that pruning never evicts something the book links to
+    #[serde(skip)]
+    used_this_run: bool,
+}
+
+/// A summary of what is in the cache
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub total_size: u64,
+    pub oldest_last_used: Option<SystemTime>,
+    pub newest_last_used: Option<SystemTime>,
+}
+
+/// What a prune removed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PruneStats {
+    pub removed_entries: usize,
+    pub removed_size: u64,
+}
+
+impl Cache {
+    /// Opens (creating if necessary) the cache in `dir`. `max_size` (in bytes)
+    /// and `max_age` are the limits enforced by [`Cache::prune`].
+    pub fn open(dir: &Path, max_size: Option<u64>, max_age: Option<Duration>) -> Result<Cache> {
+        let cache = Cache {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_age,
+            manifest: Mutex::new(Manifest::default()),
+        };
+        let entries_dir = cache.entries_dir();
+        // `files_path` can point anywhere, so never take over a directory
+        // that something else put files in
+        if !entries_dir.join(MANIFEST_FILE).exists()
+            && std::fs::read_dir(&entries_dir).is_ok_and(|mut entries| entries.next().is_some())
+        {
+            return Err(eyre!(
+                "{} already exists and wasn't created by mdbook-diagrams, set `files_path` to another directory",
+                entries_dir.display()
+            ));
+        }
+        std::fs::create_dir_all(&entries_dir).wrap_err_with(|| {
+            format!(
+                "Failed to create cache directory at {}",
+                entries_dir.display()
+            )
+        })?;
+        // the manifest marks the directory as ours from the start, even if
+        // the build fails before it is saved
+        if !entries_dir.join(MANIFEST_FILE).exists() {
+            cache.save()?;
+        }
+
+        // a missing or corrupt manifest only means we forget when entries
+        // were last used, so start over rather than failing the build
+        let manifest = std::fs::read(entries_dir.join(MANIFEST_FILE))
+            .ok()
+            .and_then(|manifest| serde_json::from_slice(&manifest).ok())
+            .unwrap_or_default();
+        *cache.manifest.lock().expect("manifest lock isn't poisoned") = manifest;
+
+        Ok(cache)
+    }
+
+    /// The directory entries for the current cache version are stored in
+    pub fn entries_dir(&self) -> PathBuf {
+        self.dir.join(format!("v{CACHE_VERSION}"))
+    }
+
+    /// Where the entry with the given file name is (or would be) stored
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.entries_dir().join(name)
+    }
+
+    /// Reads an entry, marking it as used
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        let contents = std::fs::read(self.path(name)).ok()?;
+        self.touch(name, contents.len() as u64);
+        Some(contents)
+    }
+
+    /// Stores an entry, marking it as used
+    pub fn put(&self, name: &str, contents: &[u8]) -> Result<PathBuf> {
+        let path = self.path(name);
+        write_atomically(&path, contents).wrap_err_with(|| {
+            format!(
+                "Failed to write rendered diagram to the cache at {path}",
+                path = path.display()
+            )
+        })?;
+        self.touch(name, contents.len() as u64);
+        Ok(path)
+    }
+
+    fn touch(&self, name: &str, size: u64) {
+        let mut manifest = self.manifest.lock().expect("manifest lock isn't poisoned");
+        manifest.entries.insert(
+            name.to_string(),
+            Entry {
+                size,
+                last_used: now(),
+                used_this_run: true,
+            },
+        );
+    }
+
+    /// Writes the manifest back to disk
+    pub fn save(&self) -> Result<()> {
+        let manifest = self.manifest.lock().expect("manifest lock isn't poisoned");
+        let path = self.entries_dir().join(MANIFEST_FILE);
+        let contents =
+            serde_json::to_vec_pretty(&*manifest).wrap_err("Failed to serialize manifest")?;
+        write_atomically(&path, &contents)
+            .wrap_err_with(|| format!("Failed to write cache manifest at {}", path.display()))
+    }
+
+    /// Removes entries that haven't been used within `max_age`, then the
+    /// least recently used entries until the cache fits within `max_size`.
+    /// Entries used during this run are never removed. Files that aren't in
+    /// the manifest and directories left behind by older cache versions
+    /// (which have a manifest of their own) are removed too.
+    pub fn prune(&self) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        self.remove_stale_files(&mut stats)?;
+
+        let mut manifest = self.manifest.lock().expect("manifest lock isn't poisoned");
+        let mut by_age: Vec<(String, Entry)> = manifest
+            .entries
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        by_age.sort_by_key(|(_, entry)| entry.last_used);
+
+        let cutoff = self
+            .max_age
+            .map(|max_age| now().saturating_sub(max_age.as_secs()));
+        let mut total_size: u64 = by_age.iter().map(|(_, entry)| entry.size).sum();
+        for (name, entry) in by_age {
+            if entry.used_this_run {
+                continue;
+            }
+            let too_old = cutoff.is_some_and(|cutoff| entry.last_used < cutoff);
+            let too_big = self.max_size.is_some_and(|max_size| total_size > max_size);
+            if !too_old && !too_big {
+                continue;
+            }
+
+            let path = self.path(&name);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| {
+                        format!("Failed to remove cache entry at {}", path.display())
+                    });
+                }
+            }
+            manifest.entries.remove(&name);
+            total_size -= entry.size;
+            stats.removed_entries += 1;
+            stats.removed_size += entry.size;
+        }
+
+        Ok(stats)
+    }
+
+    fn remove_stale_files(&self, stats: &mut PruneStats) -> Result<()> {
+        let current = self.entries_dir();
+        let manifest = self.manifest.lock().expect("manifest lock isn't poisoned");
+        for dir_entry in read_dir(&self.dir)? {
+            let path = dir_entry.path();
+            if path == current {
+                for file in read_dir(&current)? {
+                    let name = file.file_name().to_string_lossy().to_string();
+                    if name != MANIFEST_FILE && !manifest.entries.contains_key(&name) {
+                        stats.removed_entries += 1;
+                        stats.removed_size += file.metadata().map(|m| m.len()).unwrap_or(0);
+                        remove(&file.path())?;
+                    }
+                }
+            } else if is_cache_dir(&path) {
+                let (entries, size) = dir_usage(&path);
+                stats.removed_entries += entries;
+                stats.removed_size += size;
+                remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every entry from the cache
+    pub fn clear(&self) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        for dir_entry in read_dir(&self.dir)? {
+            let path = dir_entry.path();
+            if is_cache_dir(&path) {
+                let (entries, size) = dir_usage(&path);
+                stats.removed_entries += entries;
+                stats.removed_size += size;
+                remove(&path)?;
+            }
+        }
+        self.manifest
+            .lock()
+            .expect("manifest lock isn't poisoned")
+            .entries
+            .clear();
+        std::fs::create_dir_all(self.entries_dir())
+            .wrap_err("Failed to re-create the cache directory")?;
+        Ok(stats)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let manifest = self.manifest.lock().expect("manifest lock isn't poisoned");
+        let last_used = manifest
+            .entries
+            .values()
+            .map(|entry| UNIX_EPOCH + Duration::from_secs(entry.last_used));
+        CacheStats {
+            entries: manifest.entries.len(),
+            total_size: manifest.entries.values().map(|entry| entry.size).sum(),
+            oldest_last_used: last_used.clone().min(),
+            newest_last_used: last_used.max(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<std::fs::DirEntry>> {
+    std::fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .wrap_err_with(|| format!("Failed to read cache directory at {}", dir.display()))
+}
+
+/// Cache directories are named `v<version>` and hold a manifest, anything
+/// else in the cache directory isn't ours to touch
+fn is_cache_dir(path: &Path) -> bool {
+    path.join(MANIFEST_FILE).is_file()
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix('v'))
+            .is_some_and(|version| version.parse::<u32>().is_ok())
+}
+
+fn dir_usage(dir: &Path) -> (usize, u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return (0, 0);
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name() != MANIFEST_FILE)
+        .fold((0, 0), |(count, size), entry| {
+            (
+                count + 1,
+                size + entry.metadata().map(|m| m.len()).unwrap_or(0),
+            )
+        })
+}
+
+/// Writes to a temporary file and renames it into place, so that nothing
+/// ever reads a file that an interrupted build only wrote part of
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
+    std::fs::write(&tmp_path, contents).and_then(|_| std::fs::rename(&tmp_path, path))
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    result.wrap_err_with(|| format!("Failed to remove {} from the cache", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(cache: &Cache, name: &str, last_used: u64, size: usize) {
+        std::fs::write(cache.path(name), vec![0; size]).unwrap();
+        cache.manifest.lock().unwrap().entries.insert(
+            name.to_string(),
+            Entry {
+                size: size as u64,
+                last_used,
+                used_this_run: false,
+            },
+        );
+    }
+
+    #[test]
+    fn prunes_old_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path(), None, Some(Duration::from_secs(60 * 60))).unwrap();
+        entry(&cache, "old.png", now() - 2 * 60 * 60, 10);
+        entry(&cache, "new.png", now() - 60, 10);
+
+        let stats = cache.prune().unwrap();
+        assert_eq!(stats.removed_entries, 1);
+        assert!(!cache.path("old.png").exists());
+        assert!(cache.path("new.png").exists());
+    }
+
+    #[test]
+    fn prunes_least_recently_used_to_fit_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path(), Some(25), None).unwrap();
+        entry(&cache, "a.png", 1, 10);
+        entry(&cache, "b.png", 2, 10);
+        entry(&cache, "c.png", 3, 10);
+        cache.put("d.png", &[0; 10]).unwrap();
+
+        let stats = cache.prune().unwrap();
+        assert_eq!(stats.removed_entries, 2);
+        assert!(!cache.path("a.png").exists());
+        assert!(!cache.path("b.png").exists());
+        assert!(cache.path("c.png").exists());
+        // entries used during the run are kept even if the cache is too big
+        assert!(cache.path("d.png").exists());
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path(), None, None).unwrap();
+        cache.put("a.svg", b"<svg/>").unwrap();
+        cache.save().unwrap();
+
+        let cache = Cache::open(dir.path(), None, None).unwrap();
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.get("a.svg").as_deref(), Some(&b"<svg/>"[..]));
+
+        let stats = cache.clear().unwrap();
+        assert_eq!(stats.removed_entries, 1);
+        assert_eq!(cache.stats(), CacheStats::default());
+        assert!(cache.get("a.svg").is_none());
+    }
+
+    #[test]
+    fn leaves_directories_it_did_not_create_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("v2");
+        std::fs::create_dir(&docs).unwrap();
+        std::fs::write(docs.join("index.md"), "# Docs").unwrap();
+
+        let cache = Cache::open(dir.path(), None, None).unwrap();
+        cache.prune().unwrap();
+        cache.clear().unwrap();
+        assert!(docs.join("index.md").exists());
+
+        std::fs::create_dir_all(dir.path().join("other/v1")).unwrap();
+        std::fs::write(dir.path().join("other/v1/notes.txt"), "mine").unwrap();
+        let error = Cache::open(&dir.path().join("other"), None, None).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("wasn't created by mdbook-diagrams"),
+            "{error}"
+        );
+    }
+}
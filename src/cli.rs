@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
@@ -16,13 +18,59 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Check whether the server supports a given renderer
+    /// Check whether the preprocessor supports a given renderer
     Supports {
         /// The renderer to check
         renderer: String,
+
+        /// The root directory of the book (containing `book.toml`)
+        #[arg(short, long, default_value = ".")]
+        book: PathBuf,
+    },
+    /// Render a single diagram, using the settings, cache and backends of a
+    /// book
+    Render {
+        /// The diagram type, guessed from the file extension if not given
+        #[arg(short = 't', long = "type")]
+        diagram_type: Option<String>,
+
+        /// The output format, instead of the one configured for the book
+        #[arg(short, long)]
+        format: Option<String>,
+
+        /// The diagram source file, or `-` to read it from stdin
+        #[arg(default_value = "-")]
+        input: PathBuf,
+
+        /// Where to write the rendered diagram (stdout if not given)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// The root directory of the book (containing `book.toml`)
+        #[arg(short, long, default_value = ".")]
+        book: PathBuf,
+    },
+    /// Manage the cache of rendered diagrams
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+
+        /// The root directory of the book (containing `book.toml`)
+        #[arg(short, long, default_value = ".")]
+        book: PathBuf,
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Remove entries that exceed the configured maximum age or size
+    Prune,
+    /// Remove every entry
+    Clear,
+    /// Show how many entries the cache holds and how much space they use
+    Stats,
+}
+
 pub fn cli() -> Cli {
     Cli::parse()
 }
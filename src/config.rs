@@ -0,0 +1,705 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use mdbook::errors::Error;
+use serde::{Deserialize, Deserializer, de, de::DeserializeOwned};
+
+use super::{
+    BackendKind, Config, DiagramOutputFormat, DiagramType, Embed, FigureNumbering, OnError,
+    RendererConfig, TypeConfig,
+};
+
+/// The table in `book.toml` that configures the preprocessor
+const TABLE: &str = "preprocessor.diagrams";
+
+/// Keys that mdbook itself reads from every `[preprocessor.<name>]` table.
+/// mdbook only uses `renderers` when it's an array, which leaves a
+/// `[preprocessor.diagrams.renderers]` table free for per-renderer settings
+const MDBOOK_KEYS: &[&str] = &["command", "before", "after", "renderers", "optional"];
+
+/// Every key of `[preprocessor.diagrams]`, used to suggest fixes for typos.
+/// Any other table is the settings for a diagram type
+const KEYS: &[&str] = &[
+    "output_format",
+    "on_error",
+    "html_embed",
+    "figure_numbering",
+    "show_source",
+    "dark_mode",
+    "dark_diagram_options",
+    "language_prefix",
+    "kroki_url",
+    "kroki_timeout_secs",
+    "kroki_timeout_sec",
+    "max_concurrent_requests",
+    "kroki_retries",
+    "kroki_retry_backoff_ms",
+    "kroki_headers",
+    "kroki_token",
+    "kroki_username",
+    "kroki_password",
+    "kroki_proxy",
+    "kroki_ca_cert",
+    "kroki_client_cert",
+    "kroki_client_key",
+    "offline",
+    "link_mode",
+    "link_preflight",
+    "filename_prefix",
+    "files_path",
+    "assets_dir",
+    "cache_max_size_mb",
+    "cache_max_age_days",
+    "diagram_options",
+    "allow_types",
+    "deny_types",
+    "allow_renderers",
+    "deny_renderers",
+    "passthrough_renderers",
+    "backends",
+    "commands",
+];
+
+/// Every key of a `[preprocessor.diagrams.<type>]` table
+const TYPE_KEYS: &[&str] = &[
+    "output_format",
+    "kroki_url",
+    "backend",
+    "command",
+    "diagram_options",
+    "dark_diagram_options",
+];
+
+/// Every key of a `[preprocessor.diagrams.renderers.<name>]` table
+const RENDERER_KEYS: &[&str] = &["output_format", "embed", "diagram_options"];
+
+/// `[preprocessor.diagrams]` as written in `book.toml`, before defaults are
+/// filled in
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    output_format: Option<Parsed<DiagramOutputFormat>>,
+    on_error: Option<Parsed<OnError>>,
+    html_embed: Option<Parsed<Embed>>,
+    figure_numbering: Option<Parsed<FigureNumbering>>,
+    show_source: Option<bool>,
+    dark_mode: Option<bool>,
+    #[serde(default)]
+    dark_diagram_options: HashMap<String, String>,
+    language_prefix: Option<String>,
+    kroki_url: Option<String>,
+    #[serde(alias = "kroki_timeout_sec")]
+    kroki_timeout_secs: Option<Number>,
+    max_concurrent_requests: Option<usize>,
+    kroki_retries: Option<u32>,
+    kroki_retry_backoff_ms: Option<u64>,
+    /// header values and credentials can refer to environment variables as
+    /// `${NAME}`
+    #[serde(default)]
+    kroki_headers: BTreeMap<String, String>,
+    kroki_token: Option<String>,
+    kroki_username: Option<String>,
+    kroki_password: Option<String>,
+    kroki_proxy: Option<String>,
+    kroki_ca_cert: Option<String>,
+    kroki_client_cert: Option<String>,
+    kroki_client_key: Option<String>,
+    offline: Option<bool>,
+    link_mode: Option<bool>,
+    link_preflight: Option<bool>,
+    filename_prefix: Option<String>,
+    files_path: Option<String>,
+    assets_dir: Option<String>,
+    cache_max_size_mb: Option<Number>,
+    cache_max_age_days: Option<Number>,
+    #[serde(default)]
+    diagram_options: HashMap<String, String>,
+    allow_types: Option<Vec<String>>,
+    #[serde(default)]
+    deny_types: Vec<String>,
+    allow_renderers: Option<Vec<String>>,
+    #[serde(default)]
+    deny_renderers: Vec<String>,
+    #[serde(default)]
+    passthrough_renderers: Vec<String>,
+    /// which backend renders each diagram type, kept alongside the `backend`
+    /// key of the diagram type tables
+    #[serde(default)]
+    backends: HashMap<String, Parsed<BackendKind>>,
+    #[serde(default)]
+    commands: HashMap<Parsed<BackendKind>, Vec<String>>,
+}
+
+/// `[preprocessor.diagrams.<type>]` as written in `book.toml`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTypeConfig {
+    output_format: Option<Parsed<DiagramOutputFormat>>,
+    kroki_url: Option<String>,
+    backend: Option<Parsed<BackendKind>>,
+    command: Option<Vec<String>>,
+    #[serde(default)]
+    diagram_options: HashMap<String, String>,
+    #[serde(default)]
+    dark_diagram_options: HashMap<String, String>,
+}
+
+/// `[preprocessor.diagrams.renderers.<name>]` as written in `book.toml`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRendererConfig {
+    output_format: Option<Parsed<DiagramOutputFormat>>,
+    embed: Option<Parsed<Embed>>,
+    #[serde(default)]
+    diagram_options: HashMap<String, String>,
+}
+
+/// A value parsed from a string with its `FromStr` implementation
+#[derive(PartialEq, Eq, Hash)]
+struct Parsed<T>(T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map(Parsed)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A quantity (seconds, megabytes, ...) that can be written as an integer or
+/// a float, but can't be negative
+#[derive(Clone, Copy)]
+struct Number(f64);
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // integers are accepted as floats too
+        let number = f64::deserialize(deserializer)?;
+        if number.is_finite() && number >= 0.0 {
+            Ok(Number(number))
+        } else {
+            Err(de::Error::invalid_value(
+                de::Unexpected::Float(number),
+                &"a non-negative number",
+            ))
+        }
+    }
+}
+
+/// Reads the preprocessor's settings from `book.toml`. Unknown keys and
+/// values of the wrong type are errors that name the offending key
+pub(crate) fn parse_config(book_config: &mdbook::Config, root: &Path) -> Result<Config, Error> {
+    let mut config = Config::default();
+    if let Some(config_in) = book_config.get_preprocessor("diagrams") {
+        let mut settings = toml::value::Table::new();
+        let mut type_tables = Vec::new();
+        let mut renderer_tables = None;
+        for (key, value) in config_in {
+            if key == "renderers"
+                && let toml::Value::Table(table) = value
+            {
+                renderer_tables = Some(table);
+                continue;
+            }
+            if MDBOOK_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            match (KEYS.contains(&key.as_str()), value) {
+                (true, _) => {
+                    settings.insert(key.clone(), value.clone());
+                }
+                (false, toml::Value::Table(table)) => type_tables.push((key, table)),
+                (false, _) => return Err(unknown_key(TABLE, key, KEYS)),
+            }
+        }
+
+        let raw: RawConfig = deserialize(toml::Value::Table(settings), TABLE)?;
+        config.kroki_headers = raw.kroki_headers(|name| std::env::var(name).ok())?;
+        raw.apply(&mut config);
+
+        let mut type_names: HashMap<DiagramType, &str> = HashMap::new();
+        for (name, table) in type_tables {
+            let diagram_type = DiagramType::from_name(name);
+            // a misspelt table like `diagram_option` or `mermiad` would
+            // otherwise be taken for a diagram type nobody uses
+            let known = [KEYS, DiagramType::NAMES].concat();
+            if matches!(diagram_type, DiagramType::Other(_)) && closest(name, &known).is_some() {
+                return Err(unknown_key(TABLE, name, &known));
+            }
+
+            // aliases like `[dot]` and `[graphviz]` would otherwise quietly
+            // overwrite each other's settings
+            if let Some(other) = type_names.insert(diagram_type.clone(), name) {
+                return Err(Error::msg(format!(
+                    "Both `{TABLE}.{other}` and `{TABLE}.{name}` configure {diagram_type} diagrams in book.toml, use only one of them"
+                )));
+            }
+
+            let path = format!("{TABLE}.{name}");
+            if let Some(key) = table.keys().find(|key| !TYPE_KEYS.contains(&key.as_str())) {
+                return Err(unknown_key(&path, key, TYPE_KEYS));
+            }
+            let raw: RawTypeConfig = deserialize(toml::Value::Table(table.clone()), &path)?;
+            raw.apply(config.types.entry(diagram_type).or_default());
+        }
+
+        for (name, value) in renderer_tables.into_iter().flatten() {
+            let path = format!("{TABLE}.renderers.{name}");
+            if let toml::Value::Table(table) = value
+                && let Some(key) = table
+                    .keys()
+                    .find(|key| !RENDERER_KEYS.contains(&key.as_str()))
+            {
+                return Err(unknown_key(&path, key, RENDERER_KEYS));
+            }
+            let raw: RawRendererConfig = deserialize(value.clone(), &path)?;
+            config.renderers.insert(name.clone(), raw.into());
+        }
+    }
+
+    // relative paths are relative to the book, not wherever we were run from
+    config.files_path = root.join(&config.files_path);
+    for path in [
+        &mut config.kroki_ca_cert,
+        &mut config.kroki_client_cert,
+        &mut config.kroki_client_key,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = root.join(&path);
+    }
+    config.root = root.to_path_buf();
+    config.src_dir = root.join(&book_config.book.src);
+    Ok(config)
+}
+
+impl RawConfig {
+    /// Builds the headers sent to Kroki, expanding environment variables with
+    /// `env` and adding an `Authorization` header for the credentials
+    fn kroki_headers(
+        &self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let expand = |key: &str, value: &str| expand_env(value, &format!("{TABLE}.{key}"), &env);
+
+        let mut headers = Vec::new();
+        for (name, value) in &self.kroki_headers {
+            headers.push((
+                name.clone(),
+                expand(&format!("kroki_headers.{name}"), value)?,
+            ));
+        }
+        match (
+            &self.kroki_token,
+            &self.kroki_username,
+            &self.kroki_password,
+        ) {
+            (Some(_), Some(_), _) => {
+                return Err(Error::msg(format!(
+                    "Only one of `{TABLE}.kroki_token` and `{TABLE}.kroki_username` can be set in book.toml"
+                )));
+            }
+            (Some(token), None, _) => {
+                let token = expand("kroki_token", token)?;
+                headers.push(("Authorization".to_string(), format!("Bearer {token}")));
+            }
+            (None, Some(username), password) => {
+                use base64::prelude::*;
+                let username = expand("kroki_username", username)?;
+                let password = match password {
+                    Some(password) => expand("kroki_password", password)?,
+                    None => String::new(),
+                };
+                let credentials = BASE64_STANDARD.encode(format!("{username}:{password}"));
+                headers.push(("Authorization".to_string(), format!("Basic {credentials}")));
+            }
+            (None, None, Some(_)) => {
+                return Err(Error::msg(format!(
+                    "`{TABLE}.kroki_password` needs a `kroki_username` in book.toml"
+                )));
+            }
+            (None, None, None) => {}
+        }
+        Ok(headers)
+    }
+
+    fn apply(self, config: &mut Config) {
+        if let Some(Parsed(output_format)) = self.output_format {
+            config.output_format = output_format;
+        }
+        if let Some(Parsed(on_error)) = self.on_error {
+            config.on_error = on_error;
+        }
+        // only used by the html renderer, see `Config::for_renderer`
+        if let Some(Parsed(html_embed)) = self.html_embed {
+            config.embed = html_embed;
+        }
+        if let Some(Parsed(figure_numbering)) = self.figure_numbering {
+            config.figure_numbering = figure_numbering;
+        }
+        if let Some(show_source) = self.show_source {
+            config.show_source = show_source;
+        }
+        if let Some(dark_mode) = self.dark_mode {
+            config.dark_mode = dark_mode;
+        }
+        config.dark_diagram_options = self.dark_diagram_options;
+        if let Some(language_prefix) = self.language_prefix {
+            config.language_prefix = language_prefix;
+        }
+        if let Some(kroki_url) = self.kroki_url {
+            config.kroki_url = kroki_url;
+        }
+        if let Some(Number(kroki_timeout_secs)) = self.kroki_timeout_secs {
+            config.kroki_timeout = Some(Duration::from_secs_f64(kroki_timeout_secs));
+        }
+        if let Some(max_concurrent_requests) = self.max_concurrent_requests {
+            config.max_concurrent_requests = max_concurrent_requests.max(1);
+        }
+        if let Some(kroki_retries) = self.kroki_retries {
+            config.kroki_retry.retries = kroki_retries;
+        }
+        if let Some(kroki_retry_backoff_ms) = self.kroki_retry_backoff_ms {
+            config.kroki_retry.backoff = Duration::from_millis(kroki_retry_backoff_ms);
+        }
+        if let Some(offline) = self.offline {
+            config.offline = offline;
+        }
+        config.kroki_proxy = self.kroki_proxy;
+        config.kroki_ca_cert = self.kroki_ca_cert.map(PathBuf::from);
+        config.kroki_client_cert = self.kroki_client_cert.map(PathBuf::from);
+        config.kroki_client_key = self.kroki_client_key.map(PathBuf::from);
+        if let Some(link_mode) = self.link_mode {
+            config.link_mode = link_mode;
+        }
+        if let Some(link_preflight) = self.link_preflight {
+            config.link_preflight = link_preflight;
+        }
+        if let Some(filename_prefix) = self.filename_prefix {
+            config.filename_prefix = filename_prefix;
+        }
+        if let Some(files_path) = self.files_path
+            && !files_path.is_empty()
+        {
+            config.files_path = PathBuf::from(files_path);
+        }
+        if let Some(assets_dir) = self.assets_dir
+            && !assets_dir.is_empty()
+        {
+            config.assets_dir = Some(PathBuf::from(assets_dir));
+        }
+        if let Some(Number(cache_max_size_mb)) = self.cache_max_size_mb {
+            config.cache_max_size = Some((cache_max_size_mb * 1024.0 * 1024.0) as u64);
+        }
+        if let Some(Number(cache_max_age_days)) = self.cache_max_age_days {
+            config.cache_max_age = Some(Duration::from_secs_f64(
+                cache_max_age_days * 24.0 * 60.0 * 60.0,
+            ));
+        }
+        config.diagram_options = self.diagram_options;
+        config.allow_types = self.allow_types.map(|names| {
+            names
+                .iter()
+                .map(|name| DiagramType::from_name(name))
+                .collect()
+        });
+        config.deny_types = self
+            .deny_types
+            .iter()
+            .map(|name| DiagramType::from_name(name))
+            .collect();
+        config.allow_renderers = self
+            .allow_renderers
+            .map(|names| names.into_iter().collect());
+        config.deny_renderers = self.deny_renderers.into_iter().collect();
+        config.passthrough_renderers = self.passthrough_renderers.into_iter().collect();
+        for (diagram_type, Parsed(backend)) in self.backends {
+            config
+                .types
+                .entry(DiagramType::from_name(&diagram_type))
+                .or_default()
+                .backend = Some(backend);
+        }
+        config.commands = self
+            .commands
+            .into_iter()
+            .map(|(Parsed(backend), command)| (backend, command))
+            .collect();
+    }
+}
+
+impl RawTypeConfig {
+    /// Merges the settings into `type_config`, which may already have a
+    /// backend from the `backends` table
+    fn apply(self, type_config: &mut TypeConfig) {
+        type_config.output_format = self.output_format.map(|Parsed(format)| format);
+        type_config.kroki_url = self.kroki_url;
+        // the diagram type's own table takes precedence
+        if let Some(Parsed(backend)) = self.backend {
+            type_config.backend = Some(backend);
+        }
+        type_config.command = self.command;
+        type_config.diagram_options = self.diagram_options;
+        type_config.dark_diagram_options = self.dark_diagram_options;
+    }
+}
+
+impl From<RawRendererConfig> for RendererConfig {
+    fn from(raw: RawRendererConfig) -> Self {
+        RendererConfig {
+            output_format: raw.output_format.map(|Parsed(format)| format),
+            embed: raw.embed.map(|Parsed(embed)| embed),
+            diagram_options: raw.diagram_options,
+        }
+    }
+}
+
+/// Replaces every `${NAME}` in the value of `key` with the environment
+/// variable `NAME`, as looked up by `env`
+fn expand_env(
+    value: &str,
+    key: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<String, Error> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + end];
+        let value = env(name).ok_or_else(|| {
+            Error::msg(format!(
+                "The environment variable `{name}` used by `{key}` in book.toml is not set"
+            ))
+        })?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Deserializes the table at `path`, reporting the full key path of any
+/// value that is invalid
+fn deserialize<T: DeserializeOwned>(value: toml::Value, path: &str) -> Result<T, Error> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let key = match e.path().to_string().as_str() {
+            "." => path.to_string(),
+            key => format!("{path}.{key}"),
+        };
+        Error::msg(format!("Invalid `{key}` in book.toml: {}", e.inner()))
+    })
+}
+
+fn unknown_key(path: &str, key: &str, keys: &[&str]) -> Error {
+    match closest(key, keys) {
+        Some(suggestion) => Error::msg(format!(
+            "Unknown key `{path}.{key}` in book.toml, did you mean `{suggestion}`?"
+        )),
+        None => Error::msg(format!("Unknown key `{path}.{key}` in book.toml")),
+    }
+}
+
+/// Finds the key that `key` is most likely a misspelling of
+fn closest<'a>(key: &str, keys: &[&'a str]) -> Option<&'a str> {
+    keys.iter()
+        .map(|candidate| (strsim::jaro_winkler(key, candidate), *candidate))
+        .filter(|(similarity, _)| *similarity > 0.85)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(book_toml: &str) -> Result<Config, Error> {
+        let book_config: mdbook::Config = book_toml.parse()?;
+        parse_config(&book_config, Path::new("/path/to/book"))
+    }
+
+    #[test]
+    fn accepts_integer_and_float_durations() {
+        let config = parse("[preprocessor.diagrams]\nkroki_timeout_secs = 5\n").unwrap();
+        assert_eq!(config.kroki_timeout, Some(Duration::from_secs(5)));
+
+        let config = parse("[preprocessor.diagrams]\nkroki_timeout_sec = 2.5\n").unwrap();
+        assert_eq!(config.kroki_timeout, Some(Duration::from_millis(2500)));
+
+        let config = parse(
+            "[preprocessor.diagrams]\ncommand = \"mdbook-diagrams\"\nafter = [\"links\"]\ncache_max_age_days = 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.cache_max_age,
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = parse("[preprocessor.diagrams]\nkroki_ulr = \"http://localhost\"\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Unknown key `preprocessor.diagrams.kroki_ulr` in book.toml, did you mean `kroki_url`?"
+        );
+
+        let error = parse("[preprocessor.diagrams.diagram_option]\ntheme = \"dark\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("did you mean `diagram_options`?"), "{error}");
+
+        let error = parse("[preprocessor.diagrams.mermiad]\ntheme = \"dark\"\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Unknown key `preprocessor.diagrams.mermiad` in book.toml, did you mean `mermaid`?"
+        );
+        let error = parse("[preprocessor.diagrams.plantuml]\noutput_format = \"svg\"\n[preprocessor.diagrams.puml]\nkroki_url = \"http://localhost\"\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Both `preprocessor.diagrams.plantuml` and `preprocessor.diagrams.puml` configure plantuml diagrams in book.toml, use only one of them"
+        );
+
+        // diagram types Kroki added since are still fine
+        assert!(
+            parse("[preprocessor.diagrams.railroad]\nkroki_url = \"http://localhost\"\n").is_ok()
+        );
+
+        let error = parse("[preprocessor.diagrams.mermaid]\nbackedn = \"mmdc\"\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Unknown key `preprocessor.diagrams.mermaid.backedn` in book.toml, did you mean `backend`?"
+        );
+    }
+
+    #[test]
+    fn reports_invalid_values_with_their_path() {
+        let error = parse("[preprocessor.diagrams]\nkroki_retries = -1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("Invalid `preprocessor.diagrams.kroki_retries` in book.toml: "),
+            "{error}"
+        );
+
+        let error = parse("[preprocessor.diagrams.plantuml.diagram_options]\nscale = 2\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with(
+                "Invalid `preprocessor.diagrams.plantuml.diagram_options.scale` in book.toml: "
+            ),
+            "{error}"
+        );
+
+        let error = parse("[preprocessor.diagrams]\noutput_format = \"gif\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Invalid output format: gif"), "{error}");
+    }
+
+    #[test]
+    fn builds_kroki_headers_from_the_environment() {
+        let raw = |book_toml: &str| -> RawConfig { toml::from_str(book_toml).unwrap() };
+        let env = |name: &str| (name == "KROKI_TOKEN").then(|| "s3cret".to_string());
+
+        let headers = raw("kroki_token = \"${KROKI_TOKEN}\"\n[kroki_headers]\nX-Team = \"docs\"\n")
+            .kroki_headers(env)
+            .unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                ("X-Team".to_string(), "docs".to_string()),
+                ("Authorization".to_string(), "Bearer s3cret".to_string())
+            ]
+        );
+
+        let headers = raw("kroki_username = \"docs\"\nkroki_password = \"${KROKI_TOKEN}\"\n")
+            .kroki_headers(env)
+            .unwrap();
+        assert_eq!(
+            headers,
+            vec![(
+                "Authorization".to_string(),
+                "Basic ZG9jczpzM2NyZXQ=".to_string()
+            )]
+        );
+
+        let error = raw("[kroki_headers]\nX-Api-Key = \"${KROKI_API_KEY}\"\n")
+            .kroki_headers(env)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "The environment variable `KROKI_API_KEY` used by `preprocessor.diagrams.kroki_headers.X-Api-Key` in book.toml is not set"
+        );
+    }
+
+    #[test]
+    fn reads_renderer_tables() {
+        let config =
+            parse("[preprocessor.diagrams]\nrenderers = [\"html\"]\nhtml_embed = \"file\"\n")
+                .unwrap();
+        assert!(config.renderers.is_empty());
+        assert_eq!(config.embed, Embed::File);
+        assert_eq!(config.for_renderer("pandoc").embed, Embed::Inline);
+
+        let config = parse(
+            "[preprocessor.diagrams.renderers.pandoc]\noutput_format = \"pdf\"\n[preprocessor.diagrams.renderers.markdown]\nembed = \"source\"\n",
+        )
+        .unwrap();
+        let pandoc = config.for_renderer("pandoc");
+        assert_eq!(pandoc.output_format, DiagramOutputFormat::Pdf);
+        assert_eq!(pandoc.embed, Embed::Inline);
+        assert_eq!(config.for_renderer("markdown").embed, Embed::Source);
+        assert_eq!(
+            config.for_renderer("html").output_format,
+            DiagramOutputFormat::Png
+        );
+
+        let error = parse("[preprocessor.diagrams.renderers.epub]\nembeds = \"file\"\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Unknown key `preprocessor.diagrams.renderers.epub.embeds` in book.toml, did you mean `embed`?"
+        );
+    }
+
+    #[test]
+    fn reads_renderer_lists() {
+        let config = parse(
+            "[preprocessor.diagrams]\nallow_renderers = [\"html\", \"epub\"]\ndeny_renderers = [\"epub\"]\npassthrough_renderers = [\"markdown\"]\n",
+        )
+        .unwrap();
+        assert!(config.supports_renderer("html"));
+        assert!(!config.supports_renderer("epub"));
+        assert!(!config.supports_renderer("linkcheck"));
+        assert_eq!(config.for_renderer("markdown").embed, Embed::Source);
+
+        let config = parse("[preprocessor.diagrams]\ndeny_renderers = [\"linkcheck\"]\n").unwrap();
+        assert!(config.supports_renderer("pandoc"));
+        assert!(!config.supports_renderer("linkcheck"));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    path::PathBuf,
+};
+
+use color_eyre::{Result, eyre::eyre};
+use mdbook::book::Chapter;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+use super::{FigureNumbering, attributes::Attributes, process::relative_link};
+
+const DIRECTIVE: &str = "{{#figref";
+
+/// How a diagram with a caption or id is labelled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Figure {
+    pub id: Option<String>,
+    /// i.e. "Figure 3.2", unless figure numbering is turned off
+    pub number: Option<String>,
+    pub caption: Option<String>,
+}
+
+impl Figure {
+    /// The text of the figure's caption, i.e. "Figure 3.2: Login flow"
+    pub fn caption_text(&self) -> Option<String> {
+        match (&self.number, &self.caption) {
+            (Some(number), Some(caption)) => Some(format!("{number}: {caption}")),
+            (Some(number), None) => Some(number.clone()),
+            (None, Some(caption)) => Some(caption.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// The text of links to the figure
+    fn reference_text(&self) -> String {
+        self.number
+            .clone()
+            .or_else(|| self.caption.clone())
+            .or_else(|| self.id.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Hands out figure numbers to the diagrams of a book in book order. Only
+/// diagrams with a caption or id are figures
+pub(crate) struct FigureCounter {
+    numbering: FigureNumbering,
+    chapter_number: Option<String>,
+    count: usize,
+}
+
+impl FigureCounter {
+    pub fn new(numbering: FigureNumbering) -> Self {
+        FigureCounter {
+            numbering,
+            chapter_number: None,
+            count: 0,
+        }
+    }
+
+    pub fn start_chapter(&mut self, chapter: &Chapter) {
+        if self.numbering == FigureNumbering::Chapter {
+            self.count = 0;
+            self.chapter_number = chapter.number.as_ref().map(|number| {
+                number
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(".")
+            });
+        }
+    }
+
+    pub fn next(&mut self, attributes: &Attributes) -> Option<Figure> {
+        if !attributes.is_figure() {
+            return None;
+        }
+
+        let number = match self.numbering {
+            FigureNumbering::None => None,
+            FigureNumbering::Chapter | FigureNumbering::Book => {
+                self.count += 1;
+                Some(match &self.chapter_number {
+                    Some(chapter_number) => format!("Figure {chapter_number}.{}", self.count),
+                    None => format!("Figure {}", self.count),
+                })
+            }
+        };
+        Some(Figure {
+            id: attributes.id.clone(),
+            number,
+            caption: attributes.caption.clone(),
+        })
+    }
+}
+
+/// Tells chapters apart between the passes over a book. Draft chapters have
+/// no path, so they are told apart by their position in reading order
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ChapterKey {
+    Path(PathBuf),
+    Draft { name: String, index: usize },
+}
+
+/// The figures of every chapter, numbered up front so that `{{#figref id}}`
+/// can link to figures later in the book
+#[derive(Default)]
+pub(crate) struct Figures {
+    /// the figures of each chapter in order
+    chapters: HashMap<ChapterKey, VecDeque<Figure>>,
+    /// figures by id, along with the path of their chapter
+    targets: HashMap<String, (Option<PathBuf>, Figure)>,
+    /// the chapter figures are inserted into or taken from
+    current: Option<ChapterKey>,
+}
+
+impl Figures {
+    /// Moves on to `chapter`, the `index`th chapter of the book in reading
+    /// order
+    pub fn start_chapter(&mut self, chapter: &Chapter, index: usize) {
+        self.current = Some(match &chapter.path {
+            Some(path) => ChapterKey::Path(path.clone()),
+            None => ChapterKey::Draft {
+                name: chapter.name.clone(),
+                index,
+            },
+        });
+    }
+
+    fn current(&self) -> ChapterKey {
+        self.current
+            .clone()
+            .expect("a chapter was started before using its figures")
+    }
+
+    /// Records the next figure in the current chapter
+    pub fn insert(&mut self, chapter: &Chapter, figure: Figure) -> Result<()> {
+        if let Some(id) = &figure.id {
+            if self.targets.contains_key(id) {
+                return Err(eyre!("Duplicate figure id: {id}"));
+            }
+            self.targets
+                .insert(id.clone(), (chapter.path.clone(), figure.clone()));
+        }
+        self.chapters
+            .entry(self.current())
+            .or_default()
+            .push_back(figure);
+        Ok(())
+    }
+
+    /// Takes the next figure in the current chapter, in the order they were
+    /// inserted
+    pub fn take(&mut self) -> Option<Figure> {
+        self.chapters.get_mut(&self.current())?.pop_front()
+    }
+
+    /// Replaces every `{{#figref id}}` in a chapter with a markdown link to the
+    /// figure, i.e. `[Figure 3.2](../guide/login.md#login-flow)`. Escaped
+    /// references (`\{{#figref ...}}`) are left for mdbook to unescape, and
+    /// references in code spans and code blocks are left as they are.
+    /// Without `linked`, the figures have no anchors to link to, so the
+    /// reference is only the figure's text.
+    /// Returns the new content and the ids that didn't match any figure
+    pub fn expand_references(&self, chapter: &Chapter, linked: bool) -> (String, Vec<String>) {
+        let content = &chapter.content;
+        let mut unknown = Vec::new();
+        if !content.contains(DIRECTIVE) {
+            return (content.clone(), unknown);
+        }
+
+        let code = code_ranges(content);
+        let mut out = String::with_capacity(content.len());
+        let mut rest = content.as_str();
+        while let Some(start) = rest.find(DIRECTIVE) {
+            let offset = content.len() - rest.len() + start;
+            let escaped =
+                rest[..start].ends_with('\\') || code.iter().any(|range| range.contains(&offset));
+            let after = &rest[start + DIRECTIVE.len()..];
+            let end = after.find("}}").filter(|end| !after[..*end].contains('\n'));
+            let (Some(end), false) = (end, escaped) else {
+                out.push_str(&rest[..start + DIRECTIVE.len()]);
+                rest = after;
+                continue;
+            };
+
+            out.push_str(&rest[..start]);
+            let id = after[..end].trim();
+            match self.targets.get(id) {
+                Some((_, figure)) if !linked => out.push_str(&figure.reference_text()),
+                Some((path, figure)) => {
+                    let link = match path {
+                        Some(path) if chapter.path.as_ref() != Some(path) => {
+                            relative_link(chapter.path.as_deref(), path)
+                        }
+                        _ => String::new(),
+                    };
+                    out.push_str(&format!("[{}]({link}#{id})", figure.reference_text()));
+                }
+                None => {
+                    unknown.push(id.to_string());
+                    out.push_str(&rest[start..start + DIRECTIVE.len() + end + "}}".len()]);
+                }
+            }
+            rest = &after[end + "}}".len()..];
+        }
+        out.push_str(rest);
+        (out, unknown)
+    }
+}
+
+/// The byte ranges of every code span and code block in `content`
+fn code_ranges(content: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(content, Options::all())
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chapter(number: Option<Vec<u32>>, path: &str, content: &str) -> Chapter {
+        let mut chapter = Chapter::new("Chapter", content.to_string(), path, Vec::new());
+        chapter.number = number.map(mdbook::book::SectionNumber);
+        chapter
+    }
+
+    fn figure_attributes(id: Option<&str>, caption: Option<&str>) -> Attributes {
+        Attributes {
+            id: id.map(str::to_string),
+            caption: caption.map(str::to_string),
+            ..Attributes::default()
+        }
+    }
+
+    #[test]
+    fn numbers_figures() {
+        let plain = Attributes::default();
+        let captioned = figure_attributes(None, Some("Login flow"));
+
+        let mut counter = FigureCounter::new(FigureNumbering::Chapter);
+        counter.start_chapter(&chapter(Some(vec![3]), "three.md", ""));
+        assert_eq!(counter.next(&plain), None);
+        assert_eq!(
+            counter.next(&captioned).and_then(|f| f.caption_text()),
+            Some("Figure 3.1: Login flow".to_string())
+        );
+        counter.start_chapter(&chapter(Some(vec![4, 1]), "four.md", ""));
+        assert_eq!(
+            counter.next(&captioned).and_then(|f| f.number),
+            Some("Figure 4.1.1".to_string())
+        );
+
+        let mut counter = FigureCounter::new(FigureNumbering::Book);
+        counter.start_chapter(&chapter(Some(vec![3]), "three.md", ""));
+        counter.next(&captioned);
+        counter.start_chapter(&chapter(Some(vec![4]), "four.md", ""));
+        assert_eq!(
+            counter.next(&captioned).and_then(|f| f.number),
+            Some("Figure 2".to_string())
+        );
+
+        let mut counter = FigureCounter::new(FigureNumbering::None);
+        assert_eq!(
+            counter.next(&captioned).and_then(|f| f.caption_text()),
+            Some("Login flow".to_string())
+        );
+    }
+
+    #[test]
+    fn expands_references() {
+        let mut counter = FigureCounter::new(FigureNumbering::Chapter);
+        let mut targets = Figures::default();
+        let login = chapter(Some(vec![3]), "guide/login.md", "");
+        counter.start_chapter(&login);
+        let figure = counter
+            .next(&figure_attributes(Some("login-flow"), None))
+            .unwrap();
+        targets.start_chapter(&login, 0);
+        targets.insert(&login, figure.clone()).unwrap();
+        assert!(targets.insert(&login, figure.clone()).is_err());
+        assert_eq!(targets.take(), Some(figure));
+        assert_eq!(targets.take(), None);
+
+        let intro = chapter(
+            None,
+            "intro.md",
+            "See {{#figref login-flow}}, \\{{#figref login-flow}} and {{#figref nope}}.",
+        );
+        let (content, unknown) = targets.expand_references(&intro, true);
+        assert_eq!(
+            content,
+            "See [Figure 3.1](guide/login.md#login-flow), \\{{#figref login-flow}} and {{#figref nope}}."
+        );
+        assert_eq!(unknown, vec!["nope".to_string()]);
+
+        let (content, _) = targets.expand_references(
+            &chapter(None, "guide/login.md", "{{#figref login-flow}}"),
+            true,
+        );
+        assert_eq!(content, "[Figure 3.1](#login-flow)");
+
+        // without anchors to link to, references are plain text
+        let (content, _) = targets.expand_references(&intro, false);
+        assert_eq!(
+            content,
+            "See Figure 3.1, \\{{#figref login-flow}} and {{#figref nope}}."
+        );
+
+        // code documenting the syntax is left alone
+        let code = "Use `{{#figref nope}}`:\n\n```text\n{{#figref nope}}\n```\n";
+        let (content, unknown) = targets.expand_references(&chapter(None, "intro.md", code), true);
+        assert_eq!(content, code);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn keeps_figures_of_drafts_apart() {
+        let draft = |name: &str| {
+            let mut draft = Chapter::new_draft(name, Vec::new());
+            draft.number = None;
+            draft
+        };
+        let figure = |caption: &str| Figure {
+            id: None,
+            number: None,
+            caption: Some(caption.to_string()),
+        };
+        let (first, second) = (draft("Draft"), draft("Draft"));
+
+        let mut figures = Figures::default();
+        figures.start_chapter(&first, 0);
+        figures.insert(&first, figure("first")).unwrap();
+        figures.start_chapter(&second, 1);
+        figures.insert(&second, figure("second")).unwrap();
+
+        figures.start_chapter(&second, 1);
+        assert_eq!(figures.take(), Some(figure("second")));
+        figures.start_chapter(&first, 0);
+        assert_eq!(figures.take(), Some(figure("first")));
+    }
+}
@@ -0,0 +1,119 @@
+use super::DiagramOutputFormat;
+
+/// Reads the intrinsic size in pixels of a rendered diagram from its header,
+/// so that pages can reserve space for an image before it has loaded
+pub(crate) fn dimensions(format: DiagramOutputFormat, contents: &[u8]) -> Option<(u32, u32)> {
+    match format {
+        DiagramOutputFormat::Png => png_dimensions(contents),
+        DiagramOutputFormat::Svg => svg_dimensions(std::str::from_utf8(contents).ok()?),
+        DiagramOutputFormat::Pdf
+        | DiagramOutputFormat::Jpeg
+        | DiagramOutputFormat::Webp
+        | DiagramOutputFormat::Txt => None,
+    }
+}
+
+/// The size of a PNG is the first thing in its IHDR chunk, which must come
+/// straight after the signature
+fn png_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !contents.starts_with(SIGNATURE) || contents.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(contents.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(contents.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+/// Uses the `width` and `height` of the root `<svg>` element if they are
+/// absolute, otherwise falls back to its `viewBox`
+fn svg_dimensions(svg: &str) -> Option<(u32, u32)> {
+    let tag = root_tag(svg)?;
+    let width = attribute(tag, "width").and_then(pixels);
+    let height = attribute(tag, "height").and_then(pixels);
+    if let (Some(width), Some(height)) = (width, height) {
+        return Some((width, height));
+    }
+
+    let view_box: Vec<f64> = attribute(tag, "viewBox")?
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match view_box[..] {
+        [_, _, width, height] if width > 0.0 && height > 0.0 => {
+            Some((width.round() as u32, height.round() as u32))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the opening `<svg ...>` tag, without the angle brackets
+fn root_tag(svg: &str) -> Option<&str> {
+    let start = svg.find("<svg")? + 1;
+    let end = start + svg[start..].find('>')?;
+    Some(&svg[start..end])
+}
+
+/// Finds the value of the attribute `name` in the inside of a tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(index) = rest.find(name) {
+        let preceded_by_space = rest[..index].ends_with(char::is_whitespace);
+        let after = rest[index + name.len()..].trim_start();
+        rest = &rest[index + name.len()..];
+
+        if let Some(value) = after.strip_prefix('=')
+            && preceded_by_space
+        {
+            let value = value.trim_start();
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &value[1..];
+            return value.find(quote).map(|end| &value[..end]);
+        }
+    }
+    None
+}
+
+/// Converts an SVG length to pixels, if it is in pixels or unitless
+fn pixels(length: &str) -> Option<u32> {
+    let length = length.trim();
+    let number = length.strip_suffix("px").unwrap_or(length);
+    let number: f64 = number.trim().parse().ok()?;
+    (number > 0.0).then(|| number.round() as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_png_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(dimensions(DiagramOutputFormat::Png, &png), Some((640, 480)));
+        assert_eq!(dimensions(DiagramOutputFormat::Png, b"GIF89a"), None);
+    }
+
+    #[test]
+    fn reads_svg_dimensions() {
+        let svg = |tag: &str| dimensions(DiagramOutputFormat::Svg, tag.as_bytes());
+        assert_eq!(
+            svg(
+                r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="120px" height="80.4"><rect stroke-width="3"/></svg>"#
+            ),
+            Some((120, 80))
+        );
+        assert_eq!(
+            svg(r#"<svg width="100%" style="max-width: 300px" viewBox="-8 -8 300.5 150">"#),
+            Some((301, 150))
+        );
+        assert_eq!(
+            svg(r#"<svg stroke-width='2' viewBox='0,0,40,20'>"#),
+            Some((40, 20))
+        );
+        assert_eq!(svg(r#"<svg width="10em" height="5em">"#), None);
+    }
+}
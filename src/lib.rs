@@ -1,4 +1,8 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use mdbook::{
     book::Book,
@@ -6,24 +10,178 @@ use mdbook::{
     preprocess::{Preprocessor, PreprocessorContext},
 };
 
+mod attributes;
+mod backend;
+pub mod cache;
+mod config;
+mod figures;
+mod image;
 mod process;
+mod source;
+mod svg;
+
+use backend::{BackendKind, RetryPolicy};
+use cache::Cache;
+use config::parse_config;
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 enum DiagramOutputFormat {
     #[default]
     Png,
     Svg,
+    /// rendered as PNG for html, which can't show PDFs in an `<img>`
+    Pdf,
+    Jpeg,
+    Webp,
+    /// ASCII art, embedded as preformatted text
+    Txt,
+}
+
+/// The diagram types Kroki can render, see https://kroki.io/#support
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DiagramType {
+    ActDiag,
+    BlockDiag,
+    Bpmn,
+    Bytefield,
+    C4PlantUml,
+    D2,
+    Dbml,
+    Ditaa,
+    Erd,
+    Excalidraw,
+    Graphviz,
+    Mermaid,
+    Nomnoml,
+    NwDiag,
+    PacketDiag,
+    Pikchr,
+    PlantUml,
+    RackDiag,
+    SeqDiag,
+    Structurizr,
+    Svgbob,
+    Symbolator,
+    Tikz,
+    Umlet,
+    Vega,
+    VegaLite,
+    WaveDrom,
+    WireViz,
+    /// any other type, i.e. one added to a self-hosted Kroki after this was
+    /// written
+    Other(String),
+}
+
+/// How rendered diagrams are included in a page
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+enum Embed {
+    /// for html, inline svgs and data URIs for everything else. Other
+    /// renderers link to the cache, or the assets directory if one is set
+    #[default]
+    Inline,
+    /// link to files written to the assets directory
+    File,
+    /// leave the code block as it is, without rendering it
+    Source,
+}
+
+/// How diagrams with a caption or id are numbered
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+enum FigureNumbering {
+    /// don't number figures
+    None,
+    /// "Figure 3.2" is the second figure in chapter 3
+    #[default]
+    Chapter,
+    /// "Figure 12" is the twelfth figure in the book
+    Book,
+}
+
+/// What to do with a diagram that fails to render
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+enum OnError {
+    /// fail the build
+    #[default]
+    Fail,
+    /// log the error and leave the code block as it was
+    Warn,
+    /// replace the code block with a box showing the error
+    Embed,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Config {
     output_format: DiagramOutputFormat,
     language_prefix: String,
     kroki_url: String,
     kroki_timeout: Option<Duration>,
+    kroki_retry: RetryPolicy,
+    /// extra headers sent with every request to Kroki, including any
+    /// credentials
+    kroki_headers: Vec<(String, String)>,
+    /// proxy for requests to Kroki, instead of any proxy set in the
+    /// environment
+    kroki_proxy: Option<String>,
+    /// PEM file with the CA certificates that Kroki's certificate is checked
+    /// against
+    kroki_ca_cert: Option<PathBuf>,
+    /// PEM file with a client certificate for mutual TLS, which may hold the
+    /// private key too
+    kroki_client_cert: Option<PathBuf>,
+    kroki_client_key: Option<PathBuf>,
+    /// only serve diagrams from the cache, never sending anything to Kroki
+    offline: bool,
+    /// link to Kroki URLs that render each diagram instead of rendering them
+    /// at build time
+    link_mode: bool,
+    /// check that each linked diagram renders while building the book
+    link_preflight: bool,
     filename_prefix: String,
     files_path: PathBuf,
     diagram_options: HashMap<String, String>,
+    /// the book's root directory, which diagrams can't include files from
+    /// outside of
+    root: PathBuf,
+    /// the book's `src` directory
+    src_dir: PathBuf,
+    /// if set, rendered diagrams are copied to this directory (relative to
+    /// `src_dir`) and linked from the chapters instead of linking to the cache
+    assets_dir: Option<PathBuf>,
+    embed: Embed,
+    figure_numbering: FigureNumbering,
+    /// add the diagram source in a collapsible `<details>` under each diagram
+    show_source: bool,
+    /// render html diagrams a second time for mdbook's dark themes
+    dark_mode: bool,
+    /// merged over the diagram options for the dark copy of each diagram
+    dark_diagram_options: HashMap<String, String>,
+    /// if set, only code blocks of these types are rendered
+    allow_types: Option<HashSet<DiagramType>>,
+    /// code blocks of these types are never rendered
+    deny_types: HashSet<DiagramType>,
+    /// settings for each diagram type that are merged over the global ones
+    types: HashMap<DiagramType, TypeConfig>,
+    /// settings for each mdbook renderer, merged over those of the diagram
+    /// type
+    renderers: HashMap<String, RendererConfig>,
+    /// if set, the preprocessor only runs for these renderers
+    allow_renderers: Option<HashSet<String>>,
+    /// the preprocessor never runs for these renderers
+    deny_renderers: HashSet<String>,
+    /// renderers that get every diagram as its source code block
+    passthrough_renderers: HashSet<String>,
+    /// overrides for the command (program and leading arguments) run by local
+    /// backends
+    commands: HashMap<BackendKind, Vec<String>>,
+    /// how many diagrams may be rendered at the same time
+    max_concurrent_requests: usize,
+    /// the cache is pruned to this many bytes after each build
+    cache_max_size: Option<u64>,
+    /// cache entries that haven't been used for this long are pruned after
+    /// each build
+    cache_max_age: Option<Duration>,
+    on_error: OnError,
 }
 
 impl Default for Config {
@@ -33,97 +191,417 @@ impl Default for Config {
             language_prefix: "".to_string(),
             kroki_url: "https://kroki.io".to_string(),
             kroki_timeout: None,
+            kroki_retry: RetryPolicy {
+                retries: 3,
+                backoff: Duration::from_millis(500),
+            },
+            kroki_headers: Vec::new(),
+            kroki_proxy: None,
+            kroki_ca_cert: None,
+            kroki_client_cert: None,
+            kroki_client_key: None,
+            offline: false,
+            link_mode: false,
+            link_preflight: false,
             filename_prefix: "diagram-".to_string(),
-            files_path: std::env::temp_dir(),
+            files_path: PathBuf::from(".diagrams-cache"),
             diagram_options: HashMap::new(),
+            root: PathBuf::from("."),
+            src_dir: PathBuf::from("src"),
+            assets_dir: None,
+            embed: Embed::Inline,
+            figure_numbering: FigureNumbering::Chapter,
+            show_source: false,
+            dark_mode: false,
+            dark_diagram_options: HashMap::new(),
+            allow_types: None,
+            deny_types: HashSet::new(),
+            types: HashMap::new(),
+            renderers: HashMap::new(),
+            allow_renderers: None,
+            deny_renderers: HashSet::new(),
+            passthrough_renderers: HashSet::new(),
+            commands: HashMap::new(),
+            max_concurrent_requests: 8,
+            cache_max_size: None,
+            cache_max_age: None,
+            on_error: OnError::Fail,
         }
     }
 }
 
-#[derive(Debug, Default)]
-pub struct DiagramsPreprocessor;
+/// Settings for a single diagram type, from `[preprocessor.diagrams.<type>]`
+#[derive(Debug, Default, Clone)]
+struct TypeConfig {
+    output_format: Option<DiagramOutputFormat>,
+    kroki_url: Option<String>,
+    /// what renders diagrams of this type, Kroki if not set
+    backend: Option<BackendKind>,
+    /// overrides the command of a local backend for this type only
+    command: Option<Vec<String>>,
+    diagram_options: HashMap<String, String>,
+    dark_diagram_options: HashMap<String, String>,
+}
 
-impl Preprocessor for DiagramsPreprocessor {
-    fn name(&self) -> &str {
-        "diagrams"
-    }
+/// Settings for a single mdbook renderer, from
+/// `[preprocessor.diagrams.renderers.<name>]`
+#[derive(Debug, Default, Clone)]
+struct RendererConfig {
+    output_format: Option<DiagramOutputFormat>,
+    embed: Option<Embed>,
+    diagram_options: HashMap<String, String>,
+}
 
-    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
-        let mut config = Config::default();
-        if let Some(config_in) = ctx.config.get_preprocessor("diagrams") {
-            if let Some(output_format) = config_in.get("output_format") {
-                if let Some(output_format) = output_format.as_str() {
-                    match output_format {
-                        "png" => config.output_format = DiagramOutputFormat::Png,
-                        "svg" => config.output_format = DiagramOutputFormat::Svg,
-                        _ => {
-                            return Err(Error::msg(format!(
-                                "Invalid output_format: {}, expected 'png' or 'svg'",
-                                output_format
-                            )));
-                        }
-                    }
-                }
+impl Config {
+    /// Returns a copy of the config with the settings for `diagram_type`
+    /// merged over the global ones
+    fn for_type(&self, diagram_type: &DiagramType) -> Config {
+        let mut config = self.clone();
+        // mermaid has a dark theme of its own
+        if *diagram_type == DiagramType::Mermaid {
+            config
+                .dark_diagram_options
+                .entry("theme".to_string())
+                .or_insert_with(|| "dark".to_string());
+        }
+        if let Some(type_config) = self.types.get(diagram_type) {
+            if let Some(output_format) = type_config.output_format {
+                config.output_format = output_format;
             }
-
-            if let Some(language_prefix) = config_in.get("language_prefix") {
-                if let Some(language_prefix) = language_prefix.as_str() {
-                    config.language_prefix = language_prefix.to_string();
-                }
+            if let Some(kroki_url) = &type_config.kroki_url {
+                config.kroki_url = kroki_url.clone();
             }
-
-            if let Some(kroki_url) = config_in.get("kroki_url") {
-                if let Some(kroki_url) = kroki_url.as_str() {
-                    config.kroki_url = kroki_url.to_string();
-                }
+            for (key, value) in &type_config.diagram_options {
+                config.diagram_options.insert(key.clone(), value.clone());
             }
-
-            if let Some(kroki_timeout_secs) = config_in.get("kroki_timeout_secs") {
-                if let Some(kroki_timeout_secs) = kroki_timeout_secs.as_float() {
-                    config.kroki_timeout = Some(Duration::from_secs_f64(kroki_timeout_secs));
-                }
+            for (key, value) in &type_config.dark_diagram_options {
+                config
+                    .dark_diagram_options
+                    .insert(key.clone(), value.clone());
             }
+        }
+        config
+    }
 
-            if let Some(filename_prefix) = config_in.get("filename_prefix") {
-                if let Some(filename_prefix) = filename_prefix.as_str() {
-                    config.filename_prefix = filename_prefix.to_string();
-                }
+    /// Returns a copy of the config with the settings for `renderer` merged
+    /// over it
+    fn for_renderer(&self, renderer: &str) -> Config {
+        let mut config = self.clone();
+        // `html_embed` only applies to html
+        if renderer != "html" {
+            config.embed = Embed::Inline;
+        }
+        if self.passthrough_renderers.contains(renderer) {
+            config.embed = Embed::Source;
+        }
+        if let Some(renderer_config) = self.renderers.get(renderer) {
+            if let Some(output_format) = renderer_config.output_format {
+                config.output_format = output_format;
             }
-
-            if let Some(files_path) = config_in.get("files_path") {
-                if let Some(files_path) = files_path.as_str() {
-                    if !files_path.is_empty() {
-                        config.files_path = PathBuf::from(files_path);
-                        std::fs::create_dir_all(&config.files_path).map_err(Error::msg)?;
-                    }
-                }
+            if let Some(embed) = renderer_config.embed {
+                config.embed = embed;
             }
-
-            if let Some(diagram_options) = config_in.get("diagram_options") {
-                if let Some(diagram_options) = diagram_options.as_table() {
-                    for (key, value) in diagram_options {
-                        if let Some(value) = value.as_str() {
-                            config
-                                .diagram_options
-                                .insert(key.to_string(), value.to_string());
-                        }
-                    }
-                }
+            for (key, value) in &renderer_config.diagram_options {
+                config.diagram_options.insert(key.clone(), value.clone());
             }
         }
+        config
+    }
+
+    /// Whether the preprocessor should run for `renderer` at all
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        self.allow_renderers
+            .as_ref()
+            .is_none_or(|allow_renderers| allow_renderers.contains(renderer))
+            && !self.deny_renderers.contains(renderer)
+    }
+}
+
+/// The preprocessor. Made with `Default`, it supports every renderer, as
+/// mdbook doesn't give `supports_renderer` the book's config
+#[derive(Debug, Default)]
+pub struct DiagramsPreprocessor {
+    config: Option<Config>,
+}
+
+impl DiagramsPreprocessor {
+    /// Creates a preprocessor that only supports the renderers allowed by
+    /// the `book.toml` in `book_root`
+    pub fn for_book(book_root: &Path) -> Result<Self, Error> {
+        let book_config = mdbook::Config::from_disk(book_root.join("book.toml"))?;
+        let config = parse_config(&book_config, book_root)?;
+        Ok(DiagramsPreprocessor {
+            config: Some(config),
+        })
+    }
+}
+
+impl Preprocessor for DiagramsPreprocessor {
+    fn name(&self) -> &str {
+        "diagrams"
+    }
 
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
+        let config = parse_config(&ctx.config, &ctx.root)?;
+        // mdbook skips `supports_renderer` when the book lists the renderers
+        // to run the preprocessor for
+        if !config.supports_renderer(&ctx.renderer) {
+            return Ok(book);
+        }
         let book = process::process(book, config, &ctx.renderer).map_err(Error::msg)?;
         Ok(book)
     }
 
-    fn supports_renderer(&self, _renderer: &str) -> bool {
-        true
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        self.config
+            .as_ref()
+            .is_none_or(|config| config.supports_renderer(renderer))
     }
 }
 
+/// Opens the diagram cache of the book in `book_root`, as configured by its
+/// `book.toml`
+pub fn open_cache(book_root: &Path) -> Result<Cache, Error> {
+    let book_config = mdbook::Config::from_disk(book_root.join("book.toml"))?;
+    let config = parse_config(&book_config, book_root)?;
+    Cache::open(
+        &config.files_path,
+        config.cache_max_size,
+        config.cache_max_age,
+    )
+    .map_err(Error::msg)
+}
+
+/// Renders a single diagram with the settings of the `book.toml` in
+/// `book_root` (or the defaults, if there isn't one), using the book's cache
+/// and backends. Without a `diagram_type`, it is guessed from the extension
+/// of `file`, the file the source was read from. `output_format` takes
+/// precedence over the configured format
+pub fn render_diagram(
+    book_root: &Path,
+    diagram_type: Option<&str>,
+    output_format: Option<&str>,
+    source: &str,
+    file: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    let book_toml = book_root.join("book.toml");
+    let book_config = match book_toml.exists() {
+        true => mdbook::Config::from_disk(book_toml)?,
+        false => mdbook::Config::default(),
+    };
+    let config = parse_config(&book_config, book_root)?;
+
+    let diagram_type = match (diagram_type, file) {
+        (Some(name), _) => DiagramType::from_name(name),
+        (None, Some(file)) => source::diagram_type_for_path(file),
+        (None, None) => {
+            return Err(Error::msg(
+                "The diagram type is needed to render a diagram from stdin",
+            ));
+        }
+    };
+    let attributes = attributes::Attributes {
+        output_format: output_format
+            .map(str::parse)
+            .transpose()
+            .map_err(Error::msg)?,
+        ..Default::default()
+    };
+    process::render_diagram(config, diagram_type, &attributes, source.to_string(), file)
+        .map_err(Error::msg)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    const PNG_1X1: &[u8] = &[
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f,
+        0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00,
+        0x01, 0x00, 0x00, 0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49,
+        0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    /// Starts a tiny stand-in for the Kroki service on a random local port so
+    /// the tests don't need network access. Every diagram is "rendered" as a
+    /// fixed image in the requested output format, except that sources
+    /// containing "invalid" get a syntax error and the first two requests for
+    /// sources containing "flaky" get a 503. Sources containing "private" get
+    /// a 401 unless the request has the bearer token "letmein". Diagrams can
+    /// be sent as JSON or encoded in a GET request's path. Returns the
+    /// service URL.
+    fn mock_kroki() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("can bind mock kroki");
+        let url = format!("http://{}", listener.local_addr().expect("has local addr"));
+        std::thread::spawn(move || {
+            let mut flaky_failures = 0;
+            for stream in listener.incoming().flatten() {
+                let _ = respond(stream, &mut flaky_failures);
+            }
+        });
+        url
+    }
+
+    fn respond(mut stream: std::net::TcpStream, flaky_failures: &mut u32) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        let mut authorization = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("authorization")
+            {
+                authorization = value.trim().to_string();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let request: serde_json::Value = match request_line.strip_prefix("GET /") {
+            Some(target) => decode_get_request(target.split(' ').next().unwrap_or_default()),
+            None => serde_json::from_slice(&body).unwrap_or_default(),
+        };
+
+        let source = request["diagram_source"].as_str().unwrap_or_default();
+        if source.contains("flaky") && *flaky_failures < 2 {
+            *flaky_failures += 1;
+            return write!(
+                stream,
+                "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+        }
+        if source.contains("private") && authorization != "Bearer letmein" {
+            return write!(
+                stream,
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+        }
+        if source.contains("invalid") {
+            let message = "Error 400: Syntax error in graph";
+            return write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{message}",
+                message.len()
+            );
+        }
+
+        // echo the diagram options back so tests can see what was sent
+        let svg = match request["diagram_options"].as_object() {
+            Some(options) if !options.is_empty() => format!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10" data-options='{}'><rect width="10" height="10"/></svg>"#,
+                request["diagram_options"]
+            ),
+            _ => r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>"#.to_string(),
+        };
+        let (content_type, image): (&str, &[u8]) = match request["output_format"].as_str() {
+            Some("svg") => ("image/svg+xml", svg.as_bytes()),
+            Some("pdf") => ("application/pdf", b"%PDF-1.7"),
+            Some("jpeg") => ("image/jpeg", b"\xff\xd8\xff\xe0"),
+            Some("txt") => ("text/plain; charset=utf-8", b"+---+\n| A |\n+---+\n"),
+            _ => ("image/png", PNG_1X1),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            image.len()
+        )?;
+        stream.write_all(image)
+    }
+
+    /// Turns `<type>/<format>/<encoded source>` into the equivalent JSON
+    /// request
+    fn decode_get_request(target: &str) -> serde_json::Value {
+        use base64::prelude::*;
+
+        let mut parts = target.splitn(3, '/');
+        let (_, output_format) = (parts.next(), parts.next());
+        let encoded = parts.next().unwrap_or_default();
+        let compressed = BASE64_URL_SAFE.decode(encoded).unwrap_or_default();
+        let mut source = String::new();
+        let _ = flate2::read::ZlibDecoder::new(&compressed[..]).read_to_string(&mut source);
+        serde_json::json!({ "diagram_source": source, "output_format": output_format })
+    }
+
+    /// Runs the preprocessor over a single-chapter book with the given
+    /// `[preprocessor.diagrams]` table and returns the chapter contents
+    fn run_chapter(
+        diagrams_config: serde_json::Value,
+        renderer: &str,
+        content: &str,
+    ) -> Result<String, Error> {
+        let chapters = run_book(
+            Path::new("/path/to/book"),
+            diagrams_config,
+            renderer,
+            &[("chapter_1.md", content)],
+        )?;
+        Ok(chapters.concat())
+    }
+
+    /// Runs the preprocessor over a book in `root` made of the given
+    /// `(path, content)` chapters, returning the content of each chapter
+    fn run_book(
+        root: &Path,
+        diagrams_config: serde_json::Value,
+        renderer: &str,
+        chapters: &[(&str, &str)],
+    ) -> Result<Vec<String>, Error> {
+        let sections: Vec<_> = chapters
+            .iter()
+            .enumerate()
+            .map(|(i, (path, content))| {
+                serde_json::json!({
+                    "Chapter": {
+                        "name": format!("Chapter {}", i + 1),
+                        "content": content,
+                        "number": [i + 1],
+                        "sub_items": [],
+                        "path": path,
+                        "source_path": path,
+                        "parent_names": []
+                    }
+                })
+            })
+            .collect();
+        let input = serde_json::json!([
+            {
+                "root": root,
+                "config": {
+                    "book": { "src": "src", "title": "TITLE" },
+                    "preprocessor": { "diagrams": diagrams_config }
+                },
+                "renderer": renderer,
+                "mdbook_version": "0.4.21"
+            },
+            {
+                "sections": sections,
+                "__non_exhaustive": null
+            }
+        ]);
+        let input = serde_json::to_vec(&input).expect("can serialize input");
+        let (ctx, book) = mdbook::preprocess::CmdPreprocessor::parse_input(input.as_slice())?;
+        let book = DiagramsPreprocessor::default().run(&ctx, book)?;
+        let mut output = Vec::new();
+        for item in book.iter() {
+            if let mdbook::book::BookItem::Chapter(chapter) = item {
+                output.push(chapter.content.clone());
+            }
+        }
+        Ok(output)
+    }
 
     #[test]
     fn render_svg_for_html() {
@@ -169,10 +647,12 @@ mod test {
             "__non_exhaustive": null
         }
         ]"##;
+        let root = tempfile::tempdir().unwrap();
+        let input_json = input_json.replace("/path/to/book", root.path().to_str().unwrap());
         let input_json = input_json.as_bytes();
 
         let (ctx, book) = mdbook::preprocess::CmdPreprocessor::parse_input(input_json).unwrap();
-        let result = DiagramsPreprocessor.run(&ctx, book);
+        let result = DiagramsPreprocessor::default().run(&ctx, book);
         assert!(result.is_ok());
 
         let mut output = String::new();
@@ -230,24 +710,975 @@ mod test {
             "__non_exhaustive": null
         }
         ]"##;
+        let root = tempfile::tempdir().unwrap();
+        let input_json = input_json.replace("/path/to/book", root.path().to_str().unwrap());
         let input_json = input_json.as_bytes();
 
         let (ctx, book) = mdbook::preprocess::CmdPreprocessor::parse_input(input_json).unwrap();
-        let result = DiagramsPreprocessor.run(&ctx, book);
+        let result = DiagramsPreprocessor::default().run(&ctx, book);
         assert!(result.is_ok());
 
         let mut output = String::new();
-        let tmp_path = std::env::temp_dir();
-        let tmp_path = tmp_path.to_str().expect("can get temp dir");
+        let cache_path = root.path().join(".diagrams-cache");
+        let cache_path = cache_path.to_str().expect("can get cache dir");
         let has_svg = result.unwrap().sections.iter().any(|item| match item {
             mdbook::book::BookItem::Chapter(chapter) => {
                 output.push_str(&chapter.content);
                 chapter
                     .content
-                    .contains(&format!("![]({tmp_path}/diagram-")) // t
+                    .contains(&format!("![]({cache_path}/v1/diagram-")) // t
             }
             _ => false,
         });
         assert!(has_svg, "Expected image link in output: {output}");
     }
+
+    #[test]
+    fn render_with_local_command_backend() {
+        let files_path = tempfile::tempdir().unwrap();
+        // stand in for graphviz with a command that echoes its input back
+        let output = run_chapter(
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "kroki_url": "http://127.0.0.1:9",
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+            }),
+            "html",
+            "# Chapter 1\n```graphviz\n<svg><text>local</text></svg>\n```\n",
+        )
+        .unwrap();
+        assert!(
+            output.contains("<text>local</text></svg>"),
+            "Expected locally rendered SVG in output: {output}"
+        );
+    }
+
+    #[test]
+    fn reject_backend_for_wrong_diagram_type() {
+        let result = run_chapter(
+            serde_json::json!({ "backends": { "mermaid": "dot" } }),
+            "html",
+            "# Chapter 1\n",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn render_many_diagrams_concurrently() {
+        let files_path = tempfile::tempdir().unwrap();
+        let mut content = String::from("# Chapter 1\n");
+        for i in 0..20 {
+            // every diagram appears twice, but should only be rendered once
+            for _ in 0..2 {
+                content.push_str(&format!("```graphviz\n<svg><text>{i}</text></svg>\n```\n"));
+            }
+        }
+        let output = run_chapter(
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "max_concurrent_requests": 4,
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+            }),
+            "html",
+            &content,
+        )
+        .unwrap();
+        for i in 0..20 {
+            let svg = format!("<text>{i}</text></svg>");
+            assert_eq!(
+                output.matches(&svg).count(),
+                2,
+                "Expected {svg} twice: {output}"
+            );
+        }
+        let cached = std::fs::read_dir(files_path.path().join("v1")).unwrap();
+        // every diagram plus the manifest
+        assert_eq!(cached.count(), 21);
+    }
+
+    #[test]
+    fn cache_key_covers_options_and_backend() {
+        let files_path = tempfile::tempdir().unwrap();
+        let log = files_path.path().join("renders.log");
+        let command = format!("echo >> '{}'; cat", log.display());
+        let render = |diagram_options: serde_json::Value, command: &str, renderer: &str| {
+            run_chapter(
+                serde_json::json!({
+                    "output_format": "svg",
+                    "files_path": files_path.path(),
+                    "diagram_options": diagram_options,
+                    "backends": { "graphviz": "dot" },
+                    "commands": { "dot": ["sh", "-c", command, "sh"] },
+                }),
+                renderer,
+                "# Chapter 1\n```graphviz\n<svg></svg>\n```\n",
+            )
+            .unwrap();
+            std::fs::read_to_string(&log).unwrap().lines().count()
+        };
+
+        assert_eq!(render(serde_json::json!({}), &command, "html"), 1);
+        // same inputs are served from the cache
+        assert_eq!(render(serde_json::json!({}), &command, "html"), 1);
+        // changing the options, the backend or the renderer invalidates the entry
+        assert_eq!(
+            render(serde_json::json!({ "theme": "dark" }), &command, "html"),
+            2
+        );
+        assert_eq!(
+            render(serde_json::json!({}), &format!("{command}; true"), "html"),
+            3
+        );
+        assert_eq!(render(serde_json::json!({}), &command, "pandoc"), 3);
+    }
+
+    #[test]
+    fn on_error_reports_every_failure() {
+        let files_path = tempfile::tempdir().unwrap();
+        let content = "# Chapter 1\n```graphviz\none\n```\n\n```graphviz\ntwo\n```\n";
+        let config = |on_error: &str| {
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "on_error": on_error,
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "echo boom >&2; exit 1", "sh"] },
+            })
+        };
+
+        let error = run_chapter(config("fail"), "html", content)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("2 diagram(s) failed to render"), "{error}");
+        assert!(error.contains("boom"), "{error}");
+
+        let output = run_chapter(config("warn"), "html", content).unwrap();
+        assert!(output.contains("```graphviz\none\n```"), "{output}");
+        assert!(output.contains("```graphviz\ntwo\n```"), "{output}");
+
+        let output = run_chapter(config("embed"), "pandoc", content).unwrap();
+        assert_eq!(
+            output.matches("Failed to render diagram").count(),
+            2,
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn embed_kroki_error_message() {
+        let files_path = tempfile::tempdir().unwrap();
+        let output = run_chapter(
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "kroki_url": mock_kroki(),
+                "on_error": "embed",
+            }),
+            "html",
+            "# Chapter 1\n```mermaid {theme=dark}\ngraph TD;\n  invalid <syntax>\n```\n",
+        )
+        .unwrap();
+        assert!(output.contains("class=\"diagram-error\""), "{output}");
+        assert!(output.contains("Syntax error in graph"), "{output}");
+        assert!(output.contains("invalid &lt;syntax&gt;"), "{output}");
+    }
+
+    #[test]
+    fn retry_transient_kroki_failures() {
+        let files_path = tempfile::tempdir().unwrap();
+        let config = |retries: u32| {
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path().join(retries.to_string()),
+                "kroki_url": mock_kroki(),
+                "kroki_retries": retries,
+                "kroki_retry_backoff_ms": 1,
+            })
+        };
+        let content = "# Chapter 1\n```mermaid\nflaky\n```\n";
+
+        let output = run_chapter(config(2), "html", content).unwrap();
+        assert!(output.contains("<svg"), "{output}");
+
+        let error = run_chapter(config(1), "html", content).unwrap_err();
+        assert!(error.to_string().contains("503"), "{error}");
+    }
+
+    #[test]
+    fn offline_mode_only_serves_cached_diagrams() {
+        let files_path = tempfile::tempdir().unwrap();
+        let kroki_url = mock_kroki();
+        let config = |offline: bool| {
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "kroki_url": kroki_url,
+                "offline": offline,
+                "on_error": "embed",
+            })
+        };
+
+        let cached = "# Chapter 1\n```mermaid\ngraph TD;\n  A-->B;\n```\n";
+        run_chapter(config(false), "html", cached).unwrap();
+        let output = run_chapter(config(true), "html", cached).unwrap();
+        assert!(output.contains("<svg"), "{output}");
+
+        let uncached = "# Chapter 1\n```mermaid\ngraph TD;\n  B-->C;\n```\n";
+        let output = run_chapter(config(true), "html", uncached).unwrap();
+        assert!(output.contains("offline mode is enabled"), "{output}");
+    }
+
+    #[test]
+    fn link_assets_relative_to_chapter() {
+        let root = tempfile::tempdir().unwrap();
+        let config = |html_embed: &str| {
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": root.path().join("cache"),
+                "assets_dir": "assets/diagrams",
+                "html_embed": html_embed,
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+            })
+        };
+        let chapters = [
+            ("index.md", "```graphviz\n<svg></svg>\n```\n"),
+            (
+                "guide/intro/setup.md",
+                "```graphviz {alt=Setup}\n<svg width=\"40\" height=\"20\"></svg>\n```\n",
+            ),
+        ];
+
+        let output = run_book(root.path(), config("inline"), "pandoc", &chapters).unwrap();
+        assert!(
+            output[0].starts_with("![](assets/diagrams/diagram-"),
+            "{output:?}"
+        );
+        assert!(
+            output[1].starts_with("![Setup](../../assets/diagrams/diagram-"),
+            "{output:?}"
+        );
+        let assets = root.path().join("src/assets/diagrams");
+        assert_eq!(std::fs::read_dir(&assets).unwrap().count(), 2);
+
+        let output = run_book(root.path(), config("file"), "html", &chapters).unwrap();
+        assert!(
+            output[1].contains("<img src=\"../../assets/diagrams/diagram-"),
+            "{output:?}"
+        );
+        assert!(!output[1].contains("<svg"), "{output:?}");
+        assert!(
+            output[1].contains("loading=\"lazy\" width=\"40\" height=\"20\""),
+            "{output:?}"
+        );
+    }
+
+    #[test]
+    fn remove_assets_of_edited_diagrams() {
+        let root = tempfile::tempdir().unwrap();
+        let render = |content: &str| {
+            run_book(
+                root.path(),
+                serde_json::json!({
+                    "output_format": "svg",
+                    "files_path": root.path().join("cache"),
+                    "html_embed": "file",
+                    "backends": { "graphviz": "dot" },
+                    "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+                }),
+                "html",
+                &[("index.md", content)],
+            )
+            .unwrap()
+        };
+        let assets = root.path().join("src/assets/diagrams");
+        let files = || {
+            let mut files: Vec<String> = std::fs::read_dir(&assets)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            files.sort();
+            files
+        };
+
+        render("```graphviz\n<svg><text>v1</text></svg>\n```\n");
+        let old = files();
+        assert_eq!(old.len(), 1);
+        std::fs::write(assets.join("notes.txt"), "").unwrap();
+        std::fs::write(assets.join("diagram-logo.svg"), "").unwrap();
+
+        render("```graphviz\n<svg><text>v2</text></svg>\n```\n");
+        let new = files();
+        assert_eq!(new.len(), 3, "{new:?}");
+        assert!(!new.contains(&old[0]), "{new:?}");
+        assert!(new.contains(&"notes.txt".to_string()), "{new:?}");
+        assert!(new.contains(&"diagram-logo.svg".to_string()), "{new:?}");
+    }
+
+    #[test]
+    fn include_diagrams_from_files() {
+        let root = tempfile::tempdir().unwrap();
+        let diagrams = root.path().join("src/diagrams");
+        std::fs::create_dir_all(&diagrams).unwrap();
+        std::fs::create_dir_all(root.path().join("src/guide")).unwrap();
+        std::fs::write(diagrams.join("flow.dot"), "<svg><text>v1</text></svg>").unwrap();
+        std::fs::write(diagrams.join("other.dot"), "<svg><text>other</text></svg>").unwrap();
+        // every render is logged, so a diagram from the escaped directive
+        // would show up as an extra line
+        let log = root.path().join("renders.log");
+        let command = format!("echo rendered >> '{}'; cat", log.display());
+        let render = || {
+            run_book(
+                root.path(),
+                serde_json::json!({
+                    "output_format": "svg",
+                    "files_path": root.path().join("cache"),
+                    "backends": { "graphviz": "dot" },
+                    "commands": { "dot": ["sh", "-c", command, "sh"] },
+                }),
+                "html",
+                &[(
+                    "guide/intro.md",
+                    "- {{#diagram ../diagrams/flow.dot}}\n- next item\n\n> {{#diagram ../diagrams/flow.dot}}\n\n{{#diagram ../diagrams/flow.dot}}\n\n```graphviz {src=\"../diagrams/flow.dot\", alt=Flow}\n```\n\n\\{{#diagram ../diagrams/other.dot}}\n",
+                )],
+            )
+            .unwrap()
+            .concat()
+        };
+        let renders = || std::fs::read_to_string(&log).unwrap().lines().count();
+
+        let output = render();
+        assert_eq!(output.matches("<text>v1</text>").count(), 4, "{output}");
+        // diagrams stay inside the list item and block quote they're in
+        assert!(output.starts_with("- <figure"), "{output}");
+        assert!(
+            output.contains("</figure>\n\n- next item\n\n> <figure"),
+            "{output}"
+        );
+        // the escaped directive is left exactly as it is for mdbook to
+        // unescape
+        assert!(
+            output.ends_with("\n\n\\{{#diagram ../diagrams/other.dot}}\n"),
+            "{output}"
+        );
+        assert!(!output.contains("<text>other</text>"), "{output}");
+        assert_eq!(renders(), 1);
+
+        std::fs::write(diagrams.join("flow.dot"), "<svg><text>v2</text></svg>").unwrap();
+        let output = render();
+        assert_eq!(output.matches("<text>v2</text>").count(), 4, "{output}");
+        assert_eq!(renders(), 2);
+    }
+
+    #[test]
+    fn number_and_reference_figures() {
+        let files_path = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "svg",
+            "files_path": files_path.path(),
+            "backends": { "graphviz": "dot" },
+            "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+        });
+        let chapters = [
+            ("intro.md", "See {{#figref login}}.\n"),
+            (
+                "guide/login.md",
+                "```graphviz\n<svg></svg>\n```\n\n```graphviz {id=login, caption=\"Login flow\"}\n<svg><text>login</text></svg>\n```\n",
+            ),
+        ];
+
+        let output = run_book(
+            Path::new("/path/to/book"),
+            config.clone(),
+            "html",
+            &chapters,
+        )
+        .unwrap();
+        assert!(
+            output[0].contains("[Figure 2.1](guide/login.md#login)"),
+            "{output:?}"
+        );
+        assert!(output[1].contains("<figure id=\"login\""), "{output:?}");
+        assert!(
+            output[1].contains("<figcaption>Figure 2.1: Login flow</figcaption>"),
+            "{output:?}"
+        );
+
+        let output = run_book(
+            Path::new("/path/to/book"),
+            config.clone(),
+            "pandoc",
+            &chapters,
+        )
+        .unwrap();
+        assert!(
+            output[1].contains("<a id=\"login\"></a>![Login flow]("),
+            "{output:?}"
+        );
+        assert!(output[1].contains("*Figure 2.1: Login flow*"), "{output:?}");
+
+        let error = run_book(
+            Path::new("/path/to/book"),
+            config.clone(),
+            "html",
+            &[("intro.md", "See {{#figref missing}}.\n")],
+        )
+        .unwrap_err();
+        let error = error.to_string();
+        assert!(
+            error.contains("1 figure reference(s) don't match any figure"),
+            "{error}"
+        );
+        assert!(error.contains("No figure with the id missing"), "{error}");
+        assert!(!error.contains("failed to render"), "{error}");
+
+        // chapters documenting the syntax keep their code as it is
+        let code = "Write `{{#figref id}}`:\n\n```text\n{{#figref id}}\n```\n";
+        let output = run_book(
+            Path::new("/path/to/book"),
+            config,
+            "html",
+            &[("intro.md", code)],
+        )
+        .unwrap();
+        assert_eq!(output[0], code);
+    }
+
+    #[test]
+    fn describe_diagrams_for_screen_readers() {
+        let kroki_url = mock_kroki();
+        let files_path = tempfile::tempdir().unwrap();
+        let render = |output_format: &str, show_source: bool| {
+            run_chapter(
+                serde_json::json!({
+                    "output_format": output_format,
+                    "files_path": files_path.path(),
+                    "kroki_url": kroki_url,
+                    "show_source": show_source,
+                }),
+                "html",
+                "```mermaid\nflowchart LR\n  accTitle: Login flow\n  accDescr: Alice <3 Bob\n  A --> B\n```\n",
+            )
+            .unwrap()
+        };
+
+        let output = render("svg", false);
+        assert!(
+            output.contains(r#"role="img" aria-label="Login flow"><title>Login flow</title><desc>Alice &lt;3 Bob</desc>"#),
+            "{output}"
+        );
+        assert!(!output.contains("<details>"), "{output}");
+
+        let output = render("png", true);
+        assert!(output.contains(r#"alt="Login flow""#), "{output}");
+        assert!(
+            output
+                .contains("<details><summary>Diagram source</summary><pre><code>flowchart LR&#10;"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn render_known_diagram_types_unless_denied() {
+        let kroki_url = mock_kroki();
+        let files_path = tempfile::tempdir().unwrap();
+        let content = "```dot\ndigraph { a -> b }\n```\n\n```tikz\n\\draw (0,0) -- (1,1);\n```\n\n```rust\nfn main() {}\n```\n";
+        let render = |lists: serde_json::Value| {
+            let mut config = serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "kroki_url": kroki_url,
+            });
+            config
+                .as_object_mut()
+                .unwrap()
+                .extend(lists.as_object().unwrap().clone());
+            run_chapter(config, "html", content).unwrap()
+        };
+
+        let output = render(serde_json::json!({}));
+        assert_eq!(output.matches("<svg").count(), 2, "{output}");
+        assert!(output.contains("```rust"), "{output}");
+
+        let output = render(serde_json::json!({ "deny_types": ["tikz"] }));
+        assert_eq!(output.matches("<svg").count(), 1, "{output}");
+        assert!(output.contains("```tikz"), "{output}");
+
+        let output = render(serde_json::json!({ "allow_types": ["tikz", "mermaid"] }));
+        assert_eq!(output.matches("<svg").count(), 1, "{output}");
+        assert!(output.contains("```dot"), "{output}");
+    }
+
+    #[test]
+    fn deny_diagram_types_included_from_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("src")).unwrap();
+        std::fs::write(root.path().join("src/flow.dot"), "<svg></svg>").unwrap();
+        let content = "{{#diagram flow.dot}}\n\n```graphviz {src=\"flow.dot\"}\n```\n";
+        let output = run_book(
+            root.path(),
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": root.path().join("cache"),
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+                "deny_types": ["graphviz"],
+            }),
+            "html",
+            &[("flow.md", content)],
+        )
+        .unwrap()
+        .concat();
+        assert!(!output.contains("<svg"), "{output}");
+        assert_eq!(
+            output.matches("```graphviz {src=\"flow.dot\"}").count(),
+            2,
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn merge_diagram_type_settings() {
+        let files_path = tempfile::tempdir().unwrap();
+        let output = run_chapter(
+            serde_json::json!({
+                "output_format": "png",
+                "files_path": files_path.path(),
+                // only the mermaid diagrams can reach Kroki
+                "kroki_url": "http://127.0.0.1:9",
+                "diagram_options": { "theme": "default", "look": "classic" },
+                "mermaid": {
+                    "output_format": "svg",
+                    "kroki_url": mock_kroki(),
+                    "diagram_options": { "theme": "dark" },
+                },
+                "graphviz": {
+                    "output_format": "svg",
+                    "backend": "dot",
+                    "command": ["sh", "-c", "cat", "sh"],
+                },
+            }),
+            "html",
+            "# Chapter 1\n```mermaid\ngraph TD;\n```\n\n```mermaid {look=neo}\ngraph LR;\n```\n\n```mermaid {format=png}\ngraph BT;\n```\n\n```graphviz\n<svg><text>local</text></svg>\n```\n",
+        )
+        .unwrap();
+        assert!(
+            output.contains(r#"data-options='{"look":"classic","theme":"dark"}'"#),
+            "Expected mermaid options merged over the global ones: {output}"
+        );
+        assert!(
+            output.contains(r#"data-options='{"look":"neo","theme":"dark"}'"#),
+            "Expected block attributes to override the mermaid options: {output}"
+        );
+        assert!(
+            output.contains("data:image/png;base64,"),
+            "Expected the block format to override the mermaid format: {output}"
+        );
+        assert!(
+            output.contains("<text>local</text></svg>"),
+            "Expected graphviz to be rendered by its own backend: {output}"
+        );
+    }
+
+    #[test]
+    fn link_to_kroki_instead_of_rendering() {
+        let files_path = tempfile::tempdir().unwrap();
+        let kroki_url = mock_kroki();
+        let content = "# Chapter 1\n```mermaid {theme=dark}\ngraph TD;\n```\n";
+        let config = |link_preflight: bool| {
+            serde_json::json!({
+                "output_format": "svg",
+                "files_path": files_path.path(),
+                "kroki_url": "http://127.0.0.1:9/",
+                "link_mode": true,
+                "link_preflight": link_preflight,
+                "mermaid": { "kroki_url": kroki_url },
+            })
+        };
+
+        let output = run_chapter(
+            serde_json::json!({
+                "files_path": files_path.path(),
+                "kroki_url": "http://kroki.example/",
+                "link_mode": true,
+            }),
+            "html",
+            content,
+        )
+        .unwrap();
+        assert!(
+            output.contains(
+                r#"<img src="http://kroki.example/mermaid/png/eNpLL0osyFAIcbHmAgAT1gMQ?theme=dark" alt="rendered diagram" loading="lazy" />"#
+            ),
+            "Expected a link to Kroki in output: {output}"
+        );
+        let output = run_chapter(
+            serde_json::json!({
+                "files_path": files_path.path(),
+                "kroki_url": "http://kroki.example",
+                "link_mode": true,
+            }),
+            "markdown",
+            content,
+        )
+        .unwrap();
+        assert!(
+            output.contains("](http://kroki.example/mermaid/png/"),
+            "Expected an image link to Kroki in output: {output}"
+        );
+
+        // the preflight check fetches every link
+        let output = run_chapter(config(true), "html", content).unwrap();
+        assert!(output.contains(&format!("src=\"{kroki_url}/mermaid/svg/")));
+        let broken = "# Chapter 1\n```mermaid\ninvalid\n```\n";
+        assert!(run_chapter(config(false), "html", broken).is_ok());
+        let error = run_chapter(config(true), "html", broken).unwrap_err();
+        assert!(
+            format!("{error:?}").contains("Syntax error in graph"),
+            "Expected the preflight check to fail: {error:?}"
+        );
+    }
+
+    #[test]
+    fn send_credentials_to_kroki() {
+        let files_path = tempfile::tempdir().unwrap();
+        let kroki_url = mock_kroki();
+        let content = "# Chapter 1\n```mermaid\nprivate\n```\n";
+        let render = |kroki_token: &str| {
+            run_chapter(
+                serde_json::json!({
+                    "files_path": files_path.path(),
+                    "kroki_url": kroki_url,
+                    "kroki_token": kroki_token,
+                    "kroki_headers": { "X-Team": "docs" },
+                }),
+                "html",
+                content,
+            )
+        };
+
+        let error = render("wrong").unwrap_err();
+        assert!(
+            format!("{error:?}").contains("401 Unauthorized"),
+            "Expected Kroki to reject the token: {error:?}"
+        );
+        let output = render("letmein").unwrap();
+        assert!(
+            output.contains("data:image/png;base64,"),
+            "Expected the diagram to render with the token: {output}"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_proxy_and_certificates() {
+        let book = tempfile::tempdir().unwrap();
+        std::fs::write(book.path().join("empty.pem"), "").unwrap();
+        let run = |diagrams_config: serde_json::Value| {
+            run_book(
+                book.path(),
+                diagrams_config,
+                "html",
+                &[("chapter_1.md", "")],
+            )
+        };
+
+        assert!(run(serde_json::json!({ "kroki_proxy": "ftp://proxy" })).is_err());
+        let error = run(serde_json::json!({ "kroki_ca_cert": "empty.pem" })).unwrap_err();
+        assert!(
+            error.to_string().contains("No certificates found"),
+            "{error}"
+        );
+        let error = run(serde_json::json!({ "kroki_client_cert": "missing.pem" })).unwrap_err();
+        assert!(format!("{error:?}").contains("missing.pem"), "{error:?}");
+    }
+
+    #[test]
+    fn render_dark_variants_for_dark_themes() {
+        let files_path = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "svg",
+            "files_path": files_path.path(),
+            "kroki_url": mock_kroki(),
+            "dark_mode": true,
+            "plantuml": { "dark_diagram_options": { "theme": "cyborg" } },
+        });
+        let content = "# Chapter 1\n```mermaid\ngraph TD;\n```\n\n```plantuml\nA -> B\n```\n\n```mermaid {dark_mode=false}\ngraph LR;\n```\n\n```nomnoml\n[A]\n```\n";
+
+        let output = run_chapter(config.clone(), "html", content).unwrap();
+        assert!(
+            output.contains(r#"<span class="diagram-dark"><svg xmlns="http://www.w3.org/2000/svg" width="10" height="10" data-options='{"theme":"dark"}'"#),
+            "Expected a dark mermaid diagram: {output}"
+        );
+        assert!(
+            output.contains(r#"data-options='{"theme":"cyborg"}'"#),
+            "Expected a dark plantuml diagram: {output}"
+        );
+        // only the two diagrams with dark options get a second copy
+        assert_eq!(output.matches("diagram-light\"").count(), 2, "{output}");
+        assert_eq!(output.matches("<style>").count(), 1, "{output}");
+        assert!(output.contains("html.coal .diagram-dark"));
+
+        let output = run_chapter(config, "markdown", content).unwrap();
+        assert!(!output.contains("diagram-dark"), "{output}");
+    }
+
+    #[test]
+    fn embed_each_output_format_for_the_renderer() {
+        let files_path = tempfile::tempdir().unwrap();
+        let render = |output_format: &str, renderer: &str| {
+            run_chapter(
+                serde_json::json!({
+                    "output_format": output_format,
+                    "files_path": files_path.path(),
+                    "kroki_url": mock_kroki(),
+                }),
+                renderer,
+                "# Chapter 1\n```svgbob {alt=\"A box\"}\n+---+\n```\n",
+            )
+            .unwrap()
+        };
+
+        let output = render("txt", "html");
+        assert!(
+            output
+                .contains(r#"<pre role="img" aria-label="A box">+---+&#10;| A |&#10;+---+</pre>"#),
+            "Expected preformatted text in output: {output}"
+        );
+        let output = render("txt", "markdown");
+        assert!(
+            output.contains("```text\n+---+\n| A |\n+---+\n```"),
+            "Expected a code block in output: {output}"
+        );
+        let output = render("jpeg", "html");
+        assert!(
+            output.contains("data:image/jpeg;base64,"),
+            "Expected a jpeg data URI in output: {output}"
+        );
+
+        // html can't show a PDF, so it gets a PNG
+        let output = render("pdf", "html");
+        assert!(
+            output.contains("data:image/png;base64,"),
+            "Expected a png data URI in output: {output}"
+        );
+        let output = render("pdf", "pandoc");
+        assert!(
+            output.contains(".pdf)"),
+            "Expected a link to the PDF in output: {output}"
+        );
+    }
+
+    #[test]
+    fn pick_format_and_embed_per_renderer() {
+        let root = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "png",
+            "files_path": root.path().join("cache"),
+            "kroki_url": mock_kroki(),
+            "renderers": {
+                "html": { "output_format": "svg" },
+                "pandoc": { "output_format": "pdf", "embed": "file" },
+                "markdown": { "embed": "source" },
+            },
+        });
+        let chapters = [("guide/setup.md", "```svgbob\n+---+\n```\n")];
+        let render = |renderer: &str| {
+            run_book(root.path(), config.clone(), renderer, &chapters)
+                .unwrap()
+                .concat()
+        };
+
+        let output = render("html");
+        assert!(output.contains("<svg"), "Expected an inline svg: {output}");
+        let output = render("pandoc");
+        assert!(
+            output.starts_with("![](../assets/diagrams/diagram-") && output.contains(".pdf)"),
+            "Expected a link to the PDF in the assets dir: {output}"
+        );
+        let output = render("markdown");
+        assert_eq!(output, "```svgbob\n+---+\n```\n");
+        let output = render("epub");
+        assert!(
+            output.contains(".png)"),
+            "Expected the global format for other renderers: {output}"
+        );
+    }
+
+    #[test]
+    fn support_configured_renderers() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(
+            root.path().join("book.toml"),
+            "[preprocessor.diagrams]\ndeny_renderers = [\"linkcheck\"]\n",
+        )
+        .unwrap();
+        let preprocessor = DiagramsPreprocessor::for_book(root.path()).unwrap();
+        assert!(preprocessor.supports_renderer("html"));
+        assert!(!preprocessor.supports_renderer("linkcheck"));
+        assert!(DiagramsPreprocessor::default().supports_renderer("linkcheck"));
+
+        // a denied renderer is left alone even when mdbook runs us for it
+        let config = serde_json::json!({
+            "files_path": root.path().join("cache"),
+            "deny_renderers": ["linkcheck"],
+            "passthrough_renderers": ["markdown"],
+        });
+        let content = "# Chapter 1\n```plantuml\n@startuml\nA -> B\n@enduml\n```\n";
+        let output = run_chapter(config.clone(), "linkcheck", content).unwrap();
+        assert_eq!(output, content);
+        let output = run_chapter(config, "markdown", content).unwrap();
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn preserve_markdown_around_diagrams() {
+        let root = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "svg",
+            "files_path": root.path().join("cache"),
+            "backends": { "graphviz": "dot" },
+            "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+        });
+        let before = "* item one\n+ item _two_\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n{{#include ../file.md}}\n\n";
+        let after = "\nafter \\* escaped *emphasis*\n";
+        let diagram = "> quote\n> ```graphviz\n> <svg></svg>\n> ```\n";
+        let plain = "Some *text*, 1\\. not a list\n\n```rust\nfn main() {}\n```";
+        let output = run_book(
+            root.path(),
+            config,
+            "html",
+            &[
+                ("diagrams.md", &format!("{before}{diagram}{after}")),
+                ("plain.md", plain),
+            ],
+        )
+        .unwrap();
+
+        assert!(
+            output[0].starts_with(&format!("{before}> quote\n> <figure")),
+            "{}",
+            output[0]
+        );
+        assert!(
+            output[0].ends_with(&format!("</figure>\n{after}")),
+            "{}",
+            output[0]
+        );
+        assert_eq!(output[1], plain);
+    }
+
+    #[test]
+    fn separate_diagrams_from_text_right_after_them() {
+        let root = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "svg",
+            "files_path": root.path().join("cache"),
+            "backends": { "graphviz": "dot" },
+            "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+        });
+        let content = "```graphviz {caption=Flow}\n<svg></svg>\n```\nSome *text* after\n";
+
+        let output = run_chapter(config.clone(), "html", content).unwrap();
+        assert!(
+            output.ends_with("</figure>\n\nSome *text* after\n"),
+            "{output}"
+        );
+        let output = run_chapter(config.clone(), "pandoc", content).unwrap();
+        assert!(
+            output.ends_with("\n\n*Figure 1.1: Flow*\n\nSome *text* after\n"),
+            "{output}"
+        );
+
+        // chapters with windows line endings keep them
+        let content = "```graphviz\r\n<svg></svg>\r\n```\r\nAfter\r\n";
+        let output = run_chapter(config, "html", content).unwrap();
+        assert!(output.ends_with("</figure>\r\n\r\nAfter\r\n"), "{output:?}");
+    }
+
+    #[test]
+    fn keep_diagrams_inside_list_items() {
+        let root = tempfile::tempdir().unwrap();
+        let config = serde_json::json!({
+            "output_format": "svg",
+            "files_path": root.path().join("cache"),
+            "assets_dir": "assets",
+            "backends": { "graphviz": "dot" },
+            "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+        });
+        let content = "1. ```graphviz {caption=Flow}\n   <svg></svg>\n   ```\n2. Next\n\n> - ```graphviz\n>   <svg></svg>\n>   ```\n> - Next\n";
+
+        let output = run_book(root.path(), config, "pandoc", &[("chapter_1.md", content)])
+            .unwrap()
+            .concat();
+        assert!(output.starts_with("1. ![Flow](assets/diagram-"), "{output}");
+        assert!(
+            output.contains(".svg)\n\n   *Figure 1.1: Flow*\n\n2. Next\n"),
+            "{output}"
+        );
+        assert!(output.contains("\n> - ![](assets/diagram-"), "{output}");
+        assert!(output.ends_with(".svg)\n>\n> - Next\n"), "{output}");
+    }
+
+    #[test]
+    fn reject_format_the_local_backend_cannot_render() {
+        let result = run_chapter(
+            serde_json::json!({
+                "output_format": "txt",
+                "files_path": tempfile::tempdir().unwrap().path(),
+                "backends": { "graphviz": "dot" },
+                "commands": { "dot": ["sh", "-c", "cat", "sh"] },
+            }),
+            "html",
+            "# Chapter 1\n```graphviz\ndigraph {}\n```\n",
+        );
+        let error = result.unwrap_err();
+        assert!(
+            format!("{error:?}").contains("can't render graphviz diagrams as txt"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn render_single_diagram() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(
+            root.path().join("book.toml"),
+            "[preprocessor.diagrams]\noutput_format = \"png\"\n[preprocessor.diagrams.graphviz]\nbackend = \"dot\"\ncommand = [\"sh\", \"-c\", \"cat\", \"sh\"]\n",
+        )
+        .unwrap();
+
+        let rendered = render_diagram(
+            root.path(),
+            Some("graphviz"),
+            Some("svg"),
+            "<svg></svg>",
+            None,
+        )
+        .unwrap();
+        assert_eq!(rendered, b"<svg></svg>");
+        // the type comes from the file's extension
+        let rendered = render_diagram(
+            root.path(),
+            None,
+            Some("svg"),
+            "<svg></svg>",
+            Some(Path::new("flow.dot")),
+        )
+        .unwrap();
+        assert_eq!(rendered, b"<svg></svg>");
+        assert_eq!(open_cache(root.path()).unwrap().stats().entries, 1);
+
+        let error = render_diagram(root.path(), None, None, "<svg></svg>", None).unwrap_err();
+        assert!(error.to_string().contains("diagram type"), "{error}");
+        let error =
+            render_diagram(root.path(), Some("graphviz"), Some("gif"), "", None).unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid output format: gif"),
+            "{error}"
+        );
+    }
 }
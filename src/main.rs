@@ -1,25 +1,51 @@
 mod cli;
-use cli::Commands;
+use cli::{CacheAction, Commands};
 use color_eyre::{
     Result,
     eyre::{Context, eyre},
 };
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_diagrams::DiagramsPreprocessor;
+use mdbook_diagrams::{DiagramsPreprocessor, open_cache, render_diagram};
 use semver::{Version, VersionReq};
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
 
 fn main() -> Result<()> {
     color_eyre::install()?;
     let cli = cli::cli();
-    let preprocessor = DiagramsPreprocessor;
-
-    // handle renderer checking
-    if let Some(Commands::Supports { renderer }) = cli.command {
-        if preprocessor.supports_renderer(&renderer) {
-            std::process::exit(0);
-        } else {
-            std::process::exit(1);
+    let preprocessor = DiagramsPreprocessor::default();
+
+    match cli.command {
+        // handle renderer checking
+        Some(Commands::Supports { renderer, book }) => {
+            // without a readable config, say yes and leave reporting any
+            // problem with it to the actual run
+            let preprocessor = DiagramsPreprocessor::for_book(&book).unwrap_or_default();
+            if preprocessor.supports_renderer(&renderer) {
+                std::process::exit(0);
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Render {
+            diagram_type,
+            format,
+            input,
+            output,
+            book,
+        }) => {
+            return render(
+                diagram_type.as_deref(),
+                format.as_deref(),
+                &input,
+                output.as_deref(),
+                &book,
+            );
         }
+        Some(Commands::Cache { action, book }) => return cache(action, &book),
+        None => {}
     }
 
     // now actually process
@@ -46,3 +72,89 @@ fn main() -> Result<()> {
         .wrap_err("Failed to serialize processed book to JSON")?;
     Ok(())
 }
+
+fn render(
+    diagram_type: Option<&str>,
+    format: Option<&str>,
+    input: &Path,
+    output: Option<&Path>,
+    book: &Path,
+) -> Result<()> {
+    let (source, file) = if input == Path::new("-") {
+        let mut source = String::new();
+        std::io::stdin()
+            .read_to_string(&mut source)
+            .wrap_err("Failed to read diagram from stdin")?;
+        (source, None)
+    } else {
+        let source = std::fs::read_to_string(input)
+            .wrap_err_with(|| format!("Failed to read {}", input.display()))?;
+        (source, Some(input))
+    };
+
+    let rendered = render_diagram(book, diagram_type, format, &source, file)
+        .map_err(|e| eyre!("Failed to render diagram: {e}"))?;
+    match output {
+        Some(output) => std::fs::write(output, rendered)
+            .wrap_err_with(|| format!("Failed to write {}", output.display()))?,
+        None => std::io::stdout()
+            .write_all(&rendered)
+            .wrap_err("Failed to write diagram to stdout")?,
+    }
+    Ok(())
+}
+
+fn cache(action: CacheAction, book: &std::path::Path) -> Result<()> {
+    let cache = open_cache(book).map_err(|e| eyre!("Failed to open diagram cache: {e}"))?;
+    match action {
+        CacheAction::Prune => {
+            let stats = cache.prune()?;
+            cache.save()?;
+            println!(
+                "Removed {} entries ({})",
+                stats.removed_entries,
+                format_size(stats.removed_size)
+            );
+        }
+        CacheAction::Clear => {
+            let stats = cache.clear()?;
+            cache.save()?;
+            println!(
+                "Removed {} entries ({})",
+                stats.removed_entries,
+                format_size(stats.removed_size)
+            );
+        }
+        CacheAction::Stats => {
+            let stats = cache.stats();
+            let ago = |time: Option<std::time::SystemTime>| match time {
+                Some(time) => {
+                    let elapsed = time.elapsed().unwrap_or_default().as_secs();
+                    format!("{} days ago", elapsed / (24 * 60 * 60))
+                }
+                None => "never".to_string(),
+            };
+            println!("Location:         {}", cache.dir().display());
+            println!("Entries:          {}", stats.entries);
+            println!("Total size:       {}", format_size(stats.total_size));
+            println!("Least recent use: {}", ago(stats.oldest_last_used));
+            println!("Most recent use:  {}", ago(stats.newest_last_used));
+        }
+    }
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
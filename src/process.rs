@@ -1,29 +1,91 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 
 use color_eyre::{
     Result,
     eyre::{WrapErr, eyre},
 };
-use mdbook::book::{Book, Chapter};
+use mdbook::book::{Book, BookItem, Chapter};
 use mime::Mime;
-use pulldown_cmark::{CowStr, Event, LinkType, Tag, TagEnd};
-use serde_json::json;
-use ureq::Agent;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, LinkType, Tag, TagEnd};
+
+use super::{
+    Config, DiagramOutputFormat, DiagramType, Embed, FigureNumbering, OnError,
+    attributes::{Attributes, parse_attributes, split_info_string},
+    backend::{Backend, Backends, RenderRequest, kroki_agent},
+    cache::{CACHE_VERSION, Cache},
+    figures::{Figure, FigureCounter, Figures},
+    image, source, svg,
+};
+
+/// Where diagrams are written inside the book's `src` directory when
+/// diagrams are embedded as files without an `assets_dir`
+const DEFAULT_ASSETS_DIR: &str = "assets/diagrams";
+
+/// The renderer name diagrams from the `render` command are rendered for,
+/// which keeps whatever output format was asked for
+const STANDALONE_RENDERER: &str = "standalone";
+
+/// A diagram code block found in a chapter
+struct DiagramBlock {
+    source: String,
+    diagram_type: DiagramType,
+    attributes: Result<Attributes>,
+}
 
-use super::{Config, DiagramOutputFormat};
+/// A diagram that failed to render
+struct Failure {
+    chapter: String,
+    source: String,
+    message: String,
+}
+
+/// A `{{#figref id}}` that doesn't match any figure in the book
+struct UnknownReference {
+    chapter: String,
+    id: String,
+}
+
+/// A unique diagram that needs to be rendered
+struct RenderJob {
+    /// the file name of the diagram in the cache
+    name: String,
+    hash: String,
+    path: PathBuf,
+    source: String,
+    diagram_type: DiagramType,
+    diagram_options: BTreeMap<String, String>,
+    /// in link mode, the URL that renders the diagram, if its backend can be
+    /// linked to
+    link: Option<String>,
+    /// where the diagram is copied to under the book's `src`, if anywhere
+    asset: Option<PathBuf>,
+    config: Config,
+}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum DiagramType {
-    Mermaid,
-    PlantUml,
-    Other(String),
+/// A rendered diagram, ready to be put in a chapter
+struct Diagram<'a> {
+    job: &'a RenderJob,
+    contents: &'a [u8],
 }
 
+/// Shows the copy of each diagram that matches the page's mdbook theme
+const DARK_MODE_STYLE: &str = "<style>.diagram-dark{display:none;}html.coal .diagram-dark,html.navy .diagram-dark,html.ayu .diagram-dark{display:contents;}html.coal .diagram-light,html.navy .diagram-light,html.ayu .diagram-light{display:none;}</style>";
+
 pub fn process(mut book: Book, config: Config, renderer: &str) -> Result<Book> {
-    let agent_config = Agent::config_builder()
-        .timeout_global(config.kroki_timeout)
-        .build();
-    let agent: Agent = agent_config.into();
+    let backends = Backends::new(&config, kroki_agent(&config)?)?;
+    let cache = Cache::open(
+        &config.files_path,
+        config.cache_max_size,
+        config.cache_max_age,
+    )?;
 
     let mut error: Option<color_eyre::eyre::Error> = None;
     book.for_each_mut(|item| {
@@ -32,12 +94,14 @@ pub fn process(mut book: Book, config: Config, renderer: &str) -> Result<Book> {
         }
 
         if let mdbook::BookItem::Chapter(chapter) = item {
-            if let Err(e) =
-                process_chapter(chapter, &config, &agent, renderer).wrap_err_with(|| {
-                    format!("Failed to process diagrams in chapter: {}", chapter.name)
-                })
-            {
-                error = Some(e);
+            match source::expand_directives(&chapter.content, &config) {
+                Ok(content) => chapter.content = content,
+                Err(e) => {
+                    error = Some(e.wrap_err(format!(
+                        "Failed to process diagrams in chapter: {}",
+                        chapter.name
+                    )))
+                }
             }
         }
     });
@@ -45,108 +109,560 @@ pub fn process(mut book: Book, config: Config, renderer: &str) -> Result<Book> {
         return Err(error);
     }
 
+    // first pass: collect every diagram in the book so they can be rendered
+    // concurrently rather than one chapter at a time, and number the figures
+    // in reading order so they can be referenced from any chapter
+    let mut jobs: Vec<RenderJob> = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut counter = FigureCounter::new(config.figure_numbering);
+    let mut figures = Figures::default();
+    let chapters = book.iter().filter_map(|item| match item {
+        BookItem::Chapter(chapter) => Some(chapter),
+        _ => None,
+    });
+    for (index, chapter) in chapters.enumerate() {
+        counter.start_chapter(chapter);
+        figures.start_chapter(chapter, index);
+        map_diagrams(chapter, &config, |block, _| {
+            // blocks with broken attributes are reported in the second pass
+            if let Ok(attributes) = &block.attributes {
+                if let Some(figure) = counter.next(attributes) {
+                    figures.insert(chapter, figure)?;
+                }
+                let job = RenderJob::new(
+                    block, attributes, &config, &backends, &cache, renderer, false,
+                );
+                if job.config.embed == Embed::Source {
+                    return Ok(());
+                }
+                let dark = job.has_dark_variant(renderer).then(|| {
+                    RenderJob::new(
+                        block, attributes, &config, &backends, &cache, renderer, true,
+                    )
+                });
+                for job in std::iter::once(job).chain(dark) {
+                    if seen.insert(job.path.clone()) {
+                        jobs.push(job);
+                    }
+                }
+            }
+            Ok(())
+        })
+        .wrap_err_with(|| format!("Failed to process diagrams in chapter: {}", chapter.name))?;
+    }
+
+    let rendered = render_all(&jobs, &backends, &cache, config.max_concurrent_requests);
+
+    // second pass: splice the rendered diagrams back into each chapter,
+    // collecting every failure so they can be reported together
+    let mut failures: Vec<Failure> = Vec::new();
+    let mut unknown_references: Vec<UnknownReference> = Vec::new();
+    // figures left as source code have no anchors to link to
+    let linked = config.for_renderer(renderer).embed != Embed::Source;
+    let mut index = 0;
+    for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+        figures.start_chapter(chapter, index);
+        index += 1;
+        if error.is_some() {
+            return;
+        }
+
+        let (content, unknown) = figures.expand_references(chapter, linked);
+        chapter.content = content;
+        unknown_references.extend(unknown.into_iter().map(|id| UnknownReference {
+            chapter: chapter.name.clone(),
+            id,
+        }));
+
+        match process_chapter(
+            chapter,
+            &config,
+            &backends,
+            &cache,
+            &rendered,
+            &mut figures,
+            renderer,
+        )
+        .wrap_err_with(|| format!("Failed to process diagrams in chapter: {}", chapter.name))
+        {
+            Ok(chapter_failures) => failures.extend(chapter_failures),
+            Err(e) => error = Some(e),
+        }
+    });
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    if failures.is_empty() {
+        cache.prune()?;
+        prune_assets(&config, renderer, &jobs)?;
+    }
+    // record what was used even if something failed to render, so the
+    // diagrams that did render aren't pruned next time
+    cache.save()?;
+
+    let mut problems = Vec::new();
+    if !failures.is_empty() {
+        let count = failures.len();
+        let report = failures
+            .iter()
+            .map(|failure| {
+                format!(
+                    "In chapter {}: {}\nFailing diagram:\n{}",
+                    failure.chapter, failure.message, failure.source
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        problems.push(format!("{count} diagram(s) failed to render:\n\n{report}"));
+    }
+    if !unknown_references.is_empty() {
+        let count = unknown_references.len();
+        let report = unknown_references
+            .iter()
+            .map(|reference| {
+                format!(
+                    "In chapter {}: No figure with the id {}",
+                    reference.chapter, reference.id
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        problems.push(format!(
+            "{count} figure reference(s) don't match any figure:\n\n{report}"
+        ));
+    }
+    if !problems.is_empty() {
+        let problems = problems.join("\n\n");
+        if config.on_error == OnError::Fail {
+            return Err(eyre!("{problems}"));
+        }
+        eprintln!("Warning: {problems}");
+    }
+
     Ok(book)
 }
 
-fn code_lang_diagram_type(lang: &CowStr, config: &Config) -> Option<DiagramType> {
-    match lang {
-        s if s.starts_with(format!("{}mermaid", config.language_prefix).as_str()) => {
-            Some(DiagramType::Mermaid)
-        }
-        s if s.starts_with(format!("{}plantuml", config.language_prefix).as_str()) => {
-            Some(DiagramType::PlantUml)
+/// Renders a single diagram outside of any chapter, for the `render`
+/// command. It goes through the cache like the diagrams of the book, and
+/// PlantUML includes are relative to `file`, or the working directory for
+/// diagrams read from stdin
+pub(crate) fn render_diagram(
+    mut config: Config,
+    diagram_type: DiagramType,
+    attributes: &Attributes,
+    mut source: String,
+    file: Option<&Path>,
+) -> Result<Vec<u8>> {
+    // the caller wants the rendered diagram, not a link to it
+    config.link_mode = false;
+    if diagram_type == DiagramType::PlantUml {
+        let dir = file.and_then(Path::parent).unwrap_or(Path::new(""));
+        source = source::inline_plantuml_includes(&source, dir, file, &config.root)?;
+    }
+
+    let backends = Backends::new(&config, kroki_agent(&config)?)?;
+    let cache = Cache::open(
+        &config.files_path,
+        config.cache_max_size,
+        config.cache_max_age,
+    )?;
+    let block = DiagramBlock {
+        source,
+        diagram_type,
+        attributes: Ok(attributes.clone()),
+    };
+    let job = RenderJob::new(
+        &block,
+        attributes,
+        &config,
+        &backends,
+        &cache,
+        STANDALONE_RENDERER,
+        false,
+    );
+    let contents = render(&job, &backends, &cache)?;
+    cache.save()?;
+    Ok(contents)
+}
+
+/// Calls `f` with every chapter in reading order, the order `Book::iter`
+/// visits them in (`Book::for_each_mut` visits sub-chapters first)
+fn for_each_chapter_mut(items: &mut [BookItem], f: &mut impl FnMut(&mut Chapter)) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            f(chapter);
+            for_each_chapter_mut(&mut chapter.sub_items, f);
         }
-        s if !config.language_prefix.is_empty()
-            && s.starts_with(config.language_prefix.as_str()) =>
-        {
-            Some(DiagramType::Other(
-                s.to_string()
-                    .strip_prefix(&config.language_prefix)
-                    .expect("can strip prefix")
-                    .to_string(),
-            ))
+    }
+}
+
+/// Formats an error and everything that caused it on a single line
+fn error_message(error: &color_eyre::eyre::Error) -> String {
+    error
+        .chain()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Renders every job using up to `max_concurrent` worker threads, returning
+/// the rendered diagrams (or why they couldn't be rendered) keyed by their
+/// cache path
+fn render_all(
+    jobs: &[RenderJob],
+    backends: &Backends,
+    cache: &Cache,
+    max_concurrent: usize,
+) -> HashMap<PathBuf, Result<Vec<u8>, String>> {
+    let next_job = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<Vec<u8>>>>> =
+        Mutex::new(jobs.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..max_concurrent.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let i = next_job.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(i) else {
+                        break;
+                    };
+                    let result = render(job, backends, cache);
+                    results.lock().expect("results lock isn't poisoned")[i] = Some(result);
+                }
+            });
         }
-        _ => None,
+    });
+
+    let results = results.into_inner().expect("results lock isn't poisoned");
+    jobs.iter()
+        .zip(results)
+        .map(|(job, result)| {
+            let result = result
+                .expect("every job was rendered")
+                .map_err(|e| error_message(&e));
+            (job.path.clone(), result)
+        })
+        .collect()
+}
+
+/// Works out which type of diagram a code block in `lang` is, if any. Known
+/// diagram types are always rendered, other languages only with a
+/// `language_prefix` or their own settings in `book.toml`. A block that
+/// includes its source from a file (`has_src`) is always a diagram, whatever
+/// its language. Either way the type must pass the allow and deny lists
+fn code_lang_diagram_type(lang: &str, has_src: bool, config: &Config) -> Option<DiagramType> {
+    let lang = match lang.strip_prefix(config.language_prefix.as_str()) {
+        Some(lang) => lang,
+        None if has_src => lang,
+        None => return None,
+    };
+    if lang.is_empty() {
+        return None;
     }
+
+    let diagram_type = DiagramType::from_name(lang);
+    let known = has_src
+        || !matches!(diagram_type, DiagramType::Other(_))
+        || !config.language_prefix.is_empty()
+        || config.types.contains_key(&diagram_type);
+    let allowed = config
+        .allow_types
+        .as_ref()
+        .is_none_or(|allow_types| allow_types.contains(&diagram_type))
+        && !config.deny_types.contains(&diagram_type);
+    (known && allowed).then_some(diagram_type)
 }
 
-fn process_chapter(
-    chapter: &mut Chapter,
+/// Walks the chapter's markdown, handing every diagram code block to `f` to
+/// be replaced with whatever events it pushes. Returns the byte range of each
+/// diagram block in the chapter along with its replacement; blocks that `f`
+/// pushes no events for are left as they are.
+fn map_diagrams<'a>(
+    chapter: &'a Chapter,
     config: &Config,
-    agent: &Agent,
-    renderer: &str,
-) -> Result<()> {
+    mut f: impl FnMut(&DiagramBlock, &mut Vec<Event<'a>>) -> Result<()>,
+) -> Result<Vec<(Range<usize>, Vec<Event<'a>>)>> {
     use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
 
     // mini state machine for the current plantuml tag
     let mut diagram_type: Option<DiagramType> = None;
+    let mut block_info = String::new();
+    let mut block_range = 0..0;
     let mut code_block_contents: Option<String> = None;
 
     let parser_optons = pulldown_cmark::Options::all();
-    let mut events = Vec::new();
-    for event in Parser::new_ext(&chapter.content, parser_optons) {
-        let event = match event {
-            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang))) => {
-                diagram_type = code_lang_diagram_type(lang, config);
+    let mut replacements = Vec::new();
+    for (event, range) in Parser::new_ext(&chapter.content, parser_optons).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+                let (lang, attributes) = split_info_string(info);
+                let has_src = parse_attributes(attributes).is_ok_and(|a| a.src.is_some());
+                diagram_type = code_lang_diagram_type(lang, has_src, config);
                 if diagram_type.is_some() {
+                    block_info = info.to_string();
+                    block_range = range;
                     code_block_contents = Some("".to_owned());
-                    None // eat the start of diagram code blocks
-                } else {
-                    Some(event)
                 }
             }
             Event::End(TagEnd::CodeBlock) => {
-                if let Some(diagram_type) = &diagram_type {
-                    let code_block_contents = code_block_contents
+                if let Some(diagram_type) = diagram_type.take() {
+                    let (_, attributes_str) = split_info_string(&block_info);
+                    let mut source = code_block_contents
                         .take()
                         .expect("can take code block contents");
-                    process_diagram(
-                        code_block_contents.as_str(),
-                        diagram_type.clone(),
-                        config,
-                        agent,
-                        renderer,
-                        &mut events,
-                    )
-                    .wrap_err_with(|| {
-                        format!("Failed to process diagram in chapter {}. Failing diagram:\n{code_block_contents}", chapter.name)
+                    let attributes = parse_attributes(attributes_str)
+                        .wrap_err_with(|| {
+                            format!("Failed to parse diagram attributes: {block_info}")
+                        })
+                        .and_then(|attributes| {
+                            let mut file = None;
+                            if let Some(src) = &attributes.src {
+                                if !source.trim().is_empty() {
+                                    return Err(eyre!(
+                                        "Diagram has both a src attribute and contents: {block_info}"
+                                    ));
+                                }
+                                source = source::read(chapter, config, src)?;
+                                file = Some(source::resolve(chapter, config, src));
+                            }
+
+                            // Kroki can't read the book's files, so send it
+                            // everything the diagram includes
+                            if diagram_type == DiagramType::PlantUml {
+                                let dir = match file.as_deref().and_then(Path::parent) {
+                                    Some(dir) => dir.to_path_buf(),
+                                    None => source::chapter_dir(chapter, config),
+                                };
+                                source = source::inline_plantuml_includes(
+                                    &source,
+                                    &dir,
+                                    file.as_deref(),
+                                    &config.root,
+                                )?;
+                            }
+                            Ok(attributes)
+                        });
+                    let block = DiagramBlock {
+                        source,
+                        diagram_type,
+                        attributes,
+                    };
+                    let mut events = Vec::new();
+                    f(&block, &mut events).wrap_err_with(|| {
+                        format!(
+                            "Failed to process diagram in chapter {}. Failing diagram:\n{}",
+                            chapter.name, block.source
+                        )
                     })?;
-                    None // eat the end of diagram code blocks
-                } else {
-                    Some(event)
+                    if !events.is_empty() {
+                        replacements.push((block_range.clone(), events));
+                    }
                 }
             }
             Event::Text(ref txt) => {
                 if let Some(code_block_contents) = code_block_contents.as_mut() {
                     code_block_contents.push_str(txt);
-                    None // eat the text contents of the code block
-                } else {
-                    Some(event)
                 }
             }
-            // don't touch other events
-            _ => Some(event),
+            _ => {}
+        }
+    }
+
+    Ok(replacements)
+}
+
+/// Replaces each range of `content` with its events rendered back to
+/// markdown, copying everything in between byte for byte. Each replacement
+/// is followed by a blank line, so that text right after a diagram doesn't
+/// become part of its html block or image paragraph
+fn splice(content: &str, replacements: Vec<(Range<usize>, Vec<Event>)>) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut copied = 0;
+    for (range, events) in replacements {
+        out.push_str(&content[copied..range.start]);
+        // the closing fence's line break belongs with the block
+        let mut end = range.end;
+        if !content[..end].ends_with('\n') {
+            let rest = &content[end..];
+            if rest.starts_with("\r\n") {
+                end += 2;
+            } else if rest.starts_with('\n') {
+                end += 1;
+            }
+        }
+        let newline = match content[..end].ends_with("\r\n") {
+            true => "\r\n",
+            false => "\n",
         };
 
-        if let Some(event) = event {
-            events.push(event);
+        let mut markdown = String::new();
+        pulldown_cmark_to_cmark::cmark(events.into_iter(), &mut markdown)
+            .expect("can render cmark");
+        // a block inside a list item or block quote has to indent every
+        // line to the content of the item, or repeat the `>` markers
+        let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let indent = continuation_indent(&content[line_start..range.start]);
+        for (i, line) in markdown.trim().lines().enumerate() {
+            if i > 0 {
+                out.push_str(newline);
+                match line.is_empty() {
+                    true => out.push_str(indent.trim_end()),
+                    false => out.push_str(&indent),
+                }
+            }
+            out.push_str(line);
         }
+        if content[..end].ends_with('\n') {
+            out.push_str(newline);
+        }
+
+        let rest = &content[end..];
+        let next_line = rest.lines().next().unwrap_or_default();
+        if out.ends_with('\n')
+            && !next_line
+                .trim_matches(|c: char| c == '>' || c.is_whitespace())
+                .is_empty()
+        {
+            out.push_str(indent.trim_end());
+            out.push_str(newline);
+        }
+        copied = end;
     }
+    out.push_str(&content[copied..]);
+    out
+}
 
-    let mut buf = String::with_capacity(chapter.content.len());
-    pulldown_cmark_to_cmark::cmark(events.into_iter(), &mut buf).expect("can re-render cmark");
-    chapter.content = buf;
+/// The prefix that continues the container of a block starting after
+/// `prefix` on its first line: `>` markers are kept and list markers become
+/// spaces, so `> 1. ` is continued with `>    `
+pub(crate) fn continuation_indent(prefix: &str) -> String {
+    let is_marker = |c: char| c.is_ascii_digit() || matches!(c, '-' | '*' | '+' | '.' | ')');
+    if !prefix
+        .chars()
+        .all(|c| c == '>' || c.is_whitespace() || is_marker(c))
+    {
+        return String::new();
+    }
+    prefix
+        .chars()
+        .map(|c| if is_marker(c) { ' ' } else { c })
+        .collect()
+}
 
-    Ok(())
+fn process_chapter(
+    chapter: &mut Chapter,
+    config: &Config,
+    backends: &Backends,
+    cache: &Cache,
+    rendered: &HashMap<PathBuf, Result<Vec<u8>, String>>,
+    figures: &mut Figures,
+    renderer: &str,
+) -> Result<Vec<Failure>> {
+    let mut failures = Vec::new();
+
+    let mut uses_dark_mode = false;
+    let replacements = map_diagrams(chapter, config, |block, events| {
+        let figure = match &block.attributes {
+            Ok(attributes) if attributes.is_figure() => figures.take(),
+            _ => None,
+        };
+        let result = block
+            .attributes
+            .as_ref()
+            .map_err(error_message)
+            .and_then(|attributes| {
+                let contents = |job: &RenderJob| {
+                    rendered
+                        .get(&job.path)
+                        .cloned()
+                        .unwrap_or_else(|| Err("Diagram was not rendered".to_string()))
+                };
+                let job =
+                    RenderJob::new(block, attributes, config, backends, cache, renderer, false);
+                // pushing nothing leaves the code block as it is
+                if job.config.embed == Embed::Source {
+                    return Ok(());
+                }
+                let light = contents(&job)?;
+                let dark_job = job.has_dark_variant(renderer).then(|| {
+                    RenderJob::new(block, attributes, config, backends, cache, renderer, true)
+                });
+                let dark = match &dark_job {
+                    Some(dark_job) => Some((dark_job, contents(dark_job)?)),
+                    None => None,
+                };
+                uses_dark_mode |= dark.is_some();
+                process_diagram(
+                    Diagram {
+                        job: &job,
+                        contents: &light,
+                    },
+                    dark.as_ref()
+                        .map(|(job, contents)| Diagram { job, contents }),
+                    attributes,
+                    chapter.path.as_deref(),
+                    figure.as_ref(),
+                    renderer,
+                    events,
+                )
+                .map_err(|e| error_message(&e))
+            });
+
+        if let Err(message) = result {
+            // anything pushed before the error is dropped, which leaves the
+            // code block as it was unless the error is embedded
+            events.clear();
+            if config.on_error == OnError::Embed {
+                embed_error(block, &message, renderer, events);
+            }
+            failures.push(Failure {
+                chapter: chapter.name.clone(),
+                source: block.source.clone(),
+                message,
+            });
+        }
+        Ok(())
+    })?;
+
+    // chapters without diagrams aren't touched at all
+    if !replacements.is_empty() {
+        let mut content = splice(&chapter.content, replacements);
+        if uses_dark_mode {
+            content.push_str(&format!("\n\n{DARK_MODE_STYLE}\n"));
+        }
+        chapter.content = content;
+    }
+
+    Ok(failures)
 }
 
-fn hash(diagram: &str, format: &DiagramOutputFormat, diagram_type: &DiagramType) -> String {
+/// Hashes every input that affects the rendered bytes of a diagram
+fn hash(
+    diagram: &str,
+    diagram_type: &DiagramType,
+    format: &DiagramOutputFormat,
+    diagram_options: &BTreeMap<String, String>,
+    backend: &dyn Backend,
+) -> String {
     use sha1::{Digest, Sha1};
 
     let mut hasher = Sha1::new();
-    hasher.update(diagram.as_bytes());
-    hasher.update(format.to_string().as_bytes());
-    hasher.update(diagram_type.to_string().as_bytes());
+    // length-prefix every field so that different splits of the same bytes
+    // between fields can't collide
+    let mut update = |field: &str| {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field.as_bytes());
+    };
+    update(&CACHE_VERSION.to_string());
+    update(diagram);
+    update(&format.to_string());
+    update(&diagram_type.to_string());
+    for (key, value) in diagram_options {
+        update(key);
+        update(value);
+    }
+    update(&backend.cache_key());
     let result = hasher.finalize();
 
     let mut hash = String::new();
@@ -156,182 +672,494 @@ fn hash(diagram: &str, format: &DiagramOutputFormat, diagram_type: &DiagramType)
     hash
 }
 
-fn get_filename(diagram: &str, diagram_type: &DiagramType, config: &Config) -> String {
-    let hash = hash(diagram, &config.output_format, diagram_type);
-    let Config {
-        filename_prefix,
-        output_format,
-        ..
-    } = config;
-    format!("{filename_prefix}{hash}.{output_format}")
-}
-
-fn get_tmp_filepath(diagram: &str, diagram_type: &DiagramType, config: &Config) -> PathBuf {
-    let filename = get_filename(diagram, diagram_type, config);
-    config.files_path.join(filename)
-}
-
-fn fetch_from_tmp(
-    diagram: &str,
+/// The diagram options sent to the backend: the configured options plus any
+/// tweaks needed for the current renderer
+fn diagram_options(
     diagram_type: &DiagramType,
     config: &Config,
-) -> Option<(PathBuf, Vec<u8>)> {
-    let path = get_tmp_filepath(diagram, diagram_type, config);
-    if path.exists() {
-        let contents = std::fs::read(&path).ok()?;
-        Some((path, contents))
+    renderer: &str,
+) -> BTreeMap<String, String> {
+    let mut diagram_options = BTreeMap::new();
+    if renderer != "html" && *diagram_type == DiagramType::Mermaid {
+        // html labels need to be disabled for non-html renderers otherwise
+        // the svg won't show any text (see https://github.com/typst/typst/issues/1421)
+        diagram_options.insert("html-labels".to_string(), "false".to_string());
+    }
+    for (key, value) in &config.diagram_options {
+        diagram_options.insert(key.clone(), value.clone());
+    }
+    diagram_options
+}
+
+/// Replaces a diagram block that couldn't be rendered with a box showing the
+/// error and the diagram source
+fn embed_error(block: &DiagramBlock, message: &str, renderer: &str, events: &mut Vec<Event>) {
+    if renderer == "html" {
+        // keep the whole box on one line so blank lines in the source can't
+        // end the html block early
+        let message = html_escape(message).replace('\n', "&#10;");
+        let source = html_escape(&block.source).replace('\n', "&#10;");
+        events.push(Event::Html(CowStr::from(format!(
+            "<div class=\"diagram-error\" style=\"border: 1px solid #d33; border-left-width: 4px; border-radius: 4px; padding: 0.5em 1em; margin: 1em 0; background: rgba(221, 51, 51, 0.08);\"><p><strong>Failed to render diagram:</strong> {message}</p><pre><code>{source}</code></pre></div>\n\n"
+        ))));
     } else {
-        None
+        events.push(Event::Start(Tag::BlockQuote(None)));
+        events.push(Event::Start(Tag::Paragraph));
+        events.push(Event::Start(Tag::Strong));
+        events.push(Event::Text(CowStr::from("Failed to render diagram:")));
+        events.push(Event::End(TagEnd::Strong));
+        events.push(Event::Text(CowStr::from(format!(" {message}"))));
+        events.push(Event::End(TagEnd::Paragraph));
+        events.push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+            CowStr::from("text"),
+        ))));
+        events.push(Event::Text(CowStr::from(block.source.clone())));
+        events.push(Event::End(TagEnd::CodeBlock));
+        events.push(Event::End(TagEnd::BlockQuote(None)));
     }
 }
 
-fn render_kroki(
-    diagram: &str,
-    diagram_type: DiagramType,
-    config: &Config,
-    agent: &Agent,
-    renderer: &str,
-) -> Result<(PathBuf, Vec<u8>)> {
-    let Config {
-        output_format,
-        kroki_url,
-        ..
-    } = config;
+impl RenderJob {
+    fn new(
+        block: &DiagramBlock,
+        attributes: &Attributes,
+        config: &Config,
+        backends: &Backends,
+        cache: &Cache,
+        renderer: &str,
+        dark: bool,
+    ) -> Self {
+        let mut config =
+            attributes.apply(&config.for_type(&block.diagram_type).for_renderer(renderer));
+        config.output_format = config.output_format.for_renderer(renderer);
+        if dark {
+            let dark_diagram_options = config.dark_diagram_options.clone();
+            config.diagram_options.extend(dark_diagram_options);
+        }
+        let diagram_options = diagram_options(&block.diagram_type, &config, renderer);
+        let hash = hash(
+            &block.source,
+            &block.diagram_type,
+            &config.output_format,
+            &diagram_options,
+            backends.get(&block.diagram_type),
+        );
+        let Config {
+            filename_prefix,
+            output_format,
+            ..
+        } = &config;
+        let name = format!("{filename_prefix}{hash}.{output_format}");
+        let path = cache.path(&name);
+        // text can't be linked to as an image
+        let link = if config.link_mode && config.output_format.is_image() {
+            backends.get(&block.diagram_type).link(&RenderRequest {
+                source: &block.source,
+                diagram_type: &block.diagram_type,
+                output_format: config.output_format,
+                diagram_options: &diagram_options,
+            })
+        } else {
+            None
+        };
+        // linked diagrams and text aren't files of their own
+        let asset = assets_dir(&config, renderer)
+            .filter(|_| link.is_none() && config.output_format != DiagramOutputFormat::Txt)
+            .map(|dir| dir.join(&name));
 
-    let mut diagram_options = json!({});
-    if renderer != "html" {
-        if let DiagramType::Mermaid = diagram_type {
-            // html labels need to be disabled for non-html renderers otherwise
-            // the svg won't show any text (see https://github.com/typst/typst/issues/1421)
-            diagram_options["html-labels"] = "false".into();
+        RenderJob {
+            name,
+            hash,
+            path,
+            source: block.source.clone(),
+            diagram_type: block.diagram_type.clone(),
+            diagram_options,
+            link,
+            asset,
+            config,
         }
     }
-    for (key, value) in &config.diagram_options {
-        diagram_options[key] = serde_json::Value::String(value.clone());
+
+    /// Whether the diagram is rendered a second time with the dark diagram
+    /// options, which only the html renderer can switch between
+    fn has_dark_variant(&self, renderer: &str) -> bool {
+        renderer == "html" && self.config.dark_mode && !self.config.dark_diagram_options.is_empty()
+    }
+}
+
+/// Loads the diagram from the cache, or renders it with its backend and
+/// stores the result in the cache. Linked diagrams aren't rendered at all,
+/// apart from the optional check that they render
+fn render(job: &RenderJob, backends: &Backends, cache: &Cache) -> Result<Vec<u8>> {
+    if let Some(link) = &job.link {
+        if job.config.link_preflight {
+            backends.get(&job.diagram_type).check_link(link)?;
+        }
+        return Ok(Vec::new());
     }
 
-    let req = json!({
-        "diagram_source": diagram,
-        "diagram_type": diagram_type.to_string(),
-        "output_format": output_format.to_string(),
-        "diagram_options": diagram_options
-    });
+    if let Some(contents) = cache.get(&job.name) {
+        return Ok(contents);
+    }
 
-    let mut response = agent
-        .post(kroki_url)
-        .header("Content-Type", "application/json")
-        .send_json(req)
-        .wrap_err_with(|| format!("Failed to send diagram to Kroki service at {kroki_url}"))?;
+    let rendered_diagram = backends.get(&job.diagram_type).render(&RenderRequest {
+        source: &job.source,
+        diagram_type: &job.diagram_type,
+        output_format: job.config.output_format,
+        diagram_options: &job.diagram_options,
+    })?;
+    cache.put(&job.name, &rendered_diagram)?;
 
-    let mime_type = response.headers().get("Content-Type");
-    let output_format: DiagramOutputFormat = if let Some(mime_type) = mime_type {
-        let mime_type = mime_type
-            .to_str()
-            .wrap_err("Failed to convert response mime type to string")?;
-        let mime_type: Mime = mime_type.parse().wrap_err_with(|| {
-            format!("Failed to parse response mime type as MIME type: {mime_type}",)
-        })?;
-
-        if mime_type == mime::IMAGE_SVG {
-            DiagramOutputFormat::Svg
-        } else if mime_type == mime::IMAGE_PNG {
-            DiagramOutputFormat::Png
+    Ok(rendered_diagram)
+}
+
+/// Builds an inline CSS size from the `width` / `height` block attributes,
+/// treating bare numbers as pixels
+fn size_style(attributes: &Attributes) -> Option<String> {
+    let css_length = |value: &str| {
+        if value.parse::<f64>().is_ok() {
+            format!("{value}px")
         } else {
-            return Err(eyre!(
-                "Unexpected response mime type from Kroki service: {mime_type} (expected image/svg+xml or image/png)"
-            ));
+            value.to_string()
         }
-    } else {
-        config.output_format
     };
-    if output_format != config.output_format {
-        return Err(eyre!(
-            "Kroki service returned unexpected output format: {output_format} (expected {expected_output_format})",
-            expected_output_format = config.output_format
-        ));
+
+    let mut style = Vec::new();
+    if let Some(width) = &attributes.width {
+        style.push(format!("width: {};", css_length(width)));
     }
+    if let Some(height) = &attributes.height {
+        style.push(format!("height: {};", css_length(height)));
+    }
+    (!style.is_empty()).then(|| style.join(" "))
+}
 
-    let rendered_diagram = response
-        .body_mut()
-        .read_to_vec()
-        .wrap_err("Failed to read diagram response")?;
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
 
-    let path = get_tmp_filepath(diagram, &diagram_type, config);
-    std::fs::write(&path, &rendered_diagram).wrap_err_with(|| {
-        format!(
-            "Failed to write rendered diagram to temporary file at {path}",
-            path = path.display()
-        )
-    })?;
+/// The directory under the book's `src` that `renderer` copies diagrams into,
+/// if any
+fn assets_dir(config: &Config, renderer: &str) -> Option<PathBuf> {
+    match config.embed {
+        Embed::File => Some(
+            config
+                .assets_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_ASSETS_DIR)),
+        ),
+        Embed::Inline if renderer != "html" => config.assets_dir.clone(),
+        Embed::Inline | Embed::Source => None,
+    }
+}
+
+/// Copies a rendered diagram to `asset` under the book's `src` (so that the
+/// renderer copies it into the book output) and returns a link to it relative
+/// to the chapter at `chapter_path`
+fn write_asset(
+    job: &RenderJob,
+    asset: &Path,
+    contents: &[u8],
+    chapter_path: Option<&Path>,
+) -> Result<String> {
+    let path = job.config.src_dir.join(asset);
+    // only write when something changed so `mdbook serve` doesn't see a
+    // change in `src` on every build and rebuild forever
+    if std::fs::read(&path).ok().as_deref() != Some(contents) {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).wrap_err_with(|| {
+                format!("Failed to create assets directory: {}", parent.display())
+            })?;
+        }
+        std::fs::write(&path, contents)
+            .wrap_err_with(|| format!("Failed to write diagram to {}", path.display()))?;
+    }
 
-    Ok((path, rendered_diagram))
+    Ok(relative_link(chapter_path, asset))
 }
 
-fn render(
-    diagram: &str,
-    diagram_type: DiagramType,
-    config: &Config,
-    agent: &Agent,
-    renderer: &str,
-) -> Result<(PathBuf, Vec<u8>)> {
-    if let Some((path, contents)) = fetch_from_tmp(diagram, &diagram_type, config) {
-        Ok((path, contents))
-    } else {
-        render_kroki(diagram, diagram_type, config, agent, renderer)
+/// Removes the diagrams in the assets directory that none of `jobs` copied
+/// there, i.e. older versions of diagrams that have since been edited. Only
+/// files named like the preprocessor's own (`<filename_prefix><hash>.<ext>`)
+/// are touched
+fn prune_assets(config: &Config, renderer: &str, jobs: &[RenderJob]) -> Result<()> {
+    let Some(dir) = assets_dir(&config.for_renderer(renderer), renderer) else {
+        return Ok(());
+    };
+    let dir = config.src_dir.join(dir);
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Ok(());
+    };
+    let used: HashSet<&std::ffi::OsStr> = jobs
+        .iter()
+        .filter_map(|job| job.asset.as_deref()?.file_name())
+        .collect();
+    for entry in entries {
+        let entry =
+            entry.wrap_err_with(|| format!("Failed to read assets directory {}", dir.display()))?;
+        let name = entry.file_name();
+        let is_diagram = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(config.filename_prefix.as_str()))
+            .and_then(|name| name.split_once('.'))
+            .is_some_and(|(hash, _)| {
+                !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+            });
+        if is_diagram && !used.contains(name.as_os_str()) && entry.path().is_file() {
+            std::fs::remove_file(entry.path()).wrap_err_with(|| {
+                format!("Failed to remove unused diagram {}", entry.path().display())
+            })?;
+        }
     }
+    Ok(())
 }
 
-fn process_diagram(
-    diagram: &str,
-    diagram_type: DiagramType,
-    config: &Config,
-    agent: &Agent,
-    renderer: &str,
-    events: &mut Vec<Event>,
-) -> Result<()> {
-    let (path, contents) = render(diagram, diagram_type, config, agent, renderer)
-        .wrap_err_with(|| "Failed to render diagram")?;
+/// Builds a link from the chapter at `chapter_path` to `target`, both
+/// relative to the book's `src` directory
+pub(crate) fn relative_link(chapter_path: Option<&Path>, target: &Path) -> String {
+    let depth = chapter_path
+        .and_then(Path::parent)
+        .map(|parent| parent.components().count())
+        .unwrap_or(0);
+    let mut link = "../".repeat(depth);
+    let components: Vec<_> = target
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    link.push_str(&components.join("/"));
+    link
+}
 
-    if renderer == "html" {
+/// Builds the html for a rendered diagram: an inline svg, a data URI or a
+/// link to the diagram's file or URL
+fn html_image(
+    diagram: &Diagram,
+    attributes: &Attributes,
+    chapter_path: Option<&Path>,
+    alt: Option<&str>,
+    description: Option<&str>,
+) -> Result<String> {
+    let Diagram { job, contents } = diagram;
+    let RenderJob { hash, config, .. } = job;
+    let image = if let Some(link) = &job.link {
+        let alt = html_escape(alt.unwrap_or("rendered diagram"));
+        let style = match size_style(attributes) {
+            Some(style) => format!(" style=\"{style}\""),
+            None => "".to_string(),
+        };
+        format!(
+            "<img src=\"{}\" alt=\"{alt}\" loading=\"lazy\"{style} />",
+            html_escape(link)
+        )
+    } else if config.output_format == DiagramOutputFormat::Txt {
+        // ASCII art means nothing to a screen reader, so it is labelled like
+        // an image
+        let text = String::from_utf8_lossy(contents);
+        format!(
+            "<pre role=\"img\" aria-label=\"{}\">{}</pre>",
+            html_escape(alt.unwrap_or("rendered diagram")),
+            html_escape(text.trim_end()).replace('\n', "&#10;")
+        )
+    } else if let Some(asset) = &job.asset {
+        let src = html_escape(&write_asset(job, asset, contents, chapter_path)?);
+        let alt = html_escape(alt.unwrap_or("rendered diagram"));
+
+        // the intrinsic size lets the browser reserve space for the image
+        // before it loads, while CSS keeps the aspect ratio when the image
+        // is scaled by a width or height attribute, or by mdbook's
+        // max-width
+        let mut size = String::new();
+        if let Some((width, height)) = image::dimensions(config.output_format, contents) {
+            size.push_str(&format!(" width=\"{width}\" height=\"{height}\""));
+        }
+        let style = match (size_style(attributes), &attributes.height) {
+            (Some(style), Some(_)) if attributes.width.is_none() => {
+                format!("{style} width: auto;")
+            }
+            (Some(style), Some(_)) => style,
+            (Some(style), None) => format!("{style} height: auto;"),
+            (None, _) => "height: auto;".to_string(),
+        };
+        size.push_str(&format!(" style=\"{style}\""));
+
+        format!("<img src=\"{src}\" alt=\"{alt}\" loading=\"lazy\"{size} />")
+    } else {
         match config.output_format {
             DiagramOutputFormat::Svg => {
-                let svg: String = String::from_utf8(contents).expect("valid utf-8");
+                let svg = String::from_utf8(contents.to_vec())
+                    .wrap_err("Rendered SVG is not valid UTF-8")?;
                 let svg = svg.replace(
                     r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#,
                     "",
                 );
+                // keep the ids of every diagram on a page (or in
+                // print.html) apart
+                let svg = svg::namespace_ids(&svg, &format!("d{}-", &hash[..12]));
+                let svg = svg::label(
+                    &svg,
+                    &html_escape(alt.unwrap_or("rendered diagram")),
+                    description.map(html_escape).as_deref(),
+                );
 
-                let event = Event::Html(CowStr::from(format!(
-                    "<figure style='display: flex;flex-direction: row;justify-content: center;'>{svg}</figure>\n\n"
-                )));
-                events.push(event);
-                Ok(())
+                match size_style(attributes) {
+                    Some(style) => format!("<div style='{style}'>{svg}</div>"),
+                    None => svg,
+                }
             }
-            DiagramOutputFormat::Png => {
+            DiagramOutputFormat::Png
+            | DiagramOutputFormat::Jpeg
+            | DiagramOutputFormat::Webp
+            | DiagramOutputFormat::Pdf => {
                 use base64::prelude::*;
-                let b64 = BASE64_STANDARD.encode(&contents);
+                let b64 = BASE64_STANDARD.encode(contents);
                 let mime_type = config.output_format.mime_type();
                 let uri = format!("data:{mime_type};base64,{b64}");
+                let alt = html_escape(alt.unwrap_or("rendered diagram"));
+                let size = match size_style(attributes) {
+                    Some(style) => format!(" style=\"{style}\""),
+                    None => "".to_string(),
+                };
 
-                let event = Event::Html(CowStr::from(format!(
-                    "<figure style='display: flex;flex-direction: row;justify-content: center;'><img src=\"{uri}\" alt=\"rendered diagram\" /></figure>\n\n"
-                )));
-                events.push(event);
-                Ok(())
+                format!("<img src=\"{uri}\" alt=\"{alt}\"{size} />")
             }
+            DiagramOutputFormat::Txt => unreachable!("text is embedded as preformatted text"),
         }
+    };
+    Ok(image)
+}
+
+/// Replaces a diagram block with the rendered diagram, plus its `dark`
+/// variant for mdbook's dark themes
+fn process_diagram(
+    light: Diagram,
+    dark: Option<Diagram>,
+    attributes: &Attributes,
+    chapter_path: Option<&Path>,
+    figure: Option<&Figure>,
+    renderer: &str,
+    events: &mut Vec<Event>,
+) -> Result<()> {
+    let Diagram { job, contents } = light;
+    let RenderJob {
+        path,
+        source,
+        diagram_type,
+        config,
+        ..
+    } = job;
+
+    // prefer the block's own alt text, then anything describing the diagram
+    let described = source::describe(source, diagram_type);
+    let alt = attributes
+        .alt
+        .clone()
+        .or_else(|| attributes.caption.clone())
+        .or_else(|| described.title.clone())
+        .or_else(|| described.description.clone());
+    let description = attributes
+        .description
+        .clone()
+        .or(described.description)
+        .filter(|description| Some(description) != alt.as_ref());
+
+    if renderer == "html" {
+        let mut diagram = html_image(
+            &light,
+            attributes,
+            chapter_path,
+            alt.as_deref(),
+            description.as_deref(),
+        )?;
+        if let Some(dark) = &dark {
+            diagram = format!(
+                "<span class=\"diagram-light\">{diagram}</span><span class=\"diagram-dark\">{}</span>",
+                html_image(
+                    dark,
+                    attributes,
+                    chapter_path,
+                    alt.as_deref(),
+                    description.as_deref()
+                )?
+            );
+        }
+
+        let mut html = match figure {
+            Some(figure) => {
+                let id = match &figure.id {
+                    Some(id) => format!(" id=\"{}\"", html_escape(id)),
+                    None => "".to_string(),
+                };
+                let caption = match figure.caption_text() {
+                    Some(caption) => format!("<figcaption>{}</figcaption>", html_escape(&caption)),
+                    None => "".to_string(),
+                };
+                format!(
+                    "<figure{id} style='display: flex;flex-direction: column;align-items: center;'>{diagram}{caption}</figure>"
+                )
+            }
+            None => format!(
+                "<figure style='display: flex;flex-direction: row;justify-content: center;'>{diagram}</figure>"
+            ),
+        };
+        // the source is often the most useful description of a diagram for
+        // anyone who can't see it
+        if config.show_source {
+            html.push_str(&format!(
+                "<details><summary>Diagram source</summary><pre><code>{}</code></pre></details>",
+                html_escape(source).replace('\n', "&#10;")
+            ));
+        }
+        html.push_str("\n\n");
+        events.push(Event::Html(CowStr::from(html)));
+        Ok(())
     } else {
-        let event_start = Event::Start(Tag::Image {
-            link_type: LinkType::Inline,
-            dest_url: CowStr::from(path.to_string_lossy().to_string()),
-            title: "".into(),
-            id: "".into(),
-        });
-        let event_end = Event::End(TagEnd::Image);
-
-        events.push(event_start);
-        events.push(event_end);
-        events.push(Event::Text(CowStr::from("\n\n")));
+        // an empty anchor gives `{{#figref id}}` links something to point at
+        if let Some(id) = figure.and_then(|figure| figure.id.as_ref()) {
+            events.push(Event::InlineHtml(CowStr::from(format!(
+                "<a id=\"{}\"></a>",
+                html_escape(id)
+            ))));
+        }
+
+        if config.output_format == DiagramOutputFormat::Txt {
+            // text diagrams go in a plain code block so they stay aligned
+            let text = String::from_utf8_lossy(contents);
+            events.push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+                CowStr::from("text"),
+            ))));
+            events.push(Event::Text(CowStr::from(format!("{}\n", text.trim_end()))));
+            events.push(Event::End(TagEnd::CodeBlock));
+        } else {
+            let dest_url = match (&job.link, &job.asset) {
+                (Some(link), _) => link.clone(),
+                (None, Some(asset)) => write_asset(job, asset, contents, chapter_path)?,
+                (None, None) => path.to_string_lossy().to_string(),
+            };
+            events.push(Event::Start(Tag::Image {
+                link_type: LinkType::Inline,
+                dest_url: CowStr::from(dest_url),
+                title: "".into(),
+                id: "".into(),
+            }));
+            if let Some(alt) = alt {
+                events.push(Event::Text(CowStr::from(alt)));
+            }
+            events.push(Event::End(TagEnd::Image));
+            events.push(Event::Text(CowStr::from("\n\n")));
+        }
+
+        // markdown has no captions, so put it in its own paragraph under the
+        // image
+        if let Some(caption) = figure.and_then(Figure::caption_text) {
+            events.push(Event::Start(Tag::Emphasis));
+            events.push(Event::Text(CowStr::from(caption)));
+            events.push(Event::End(TagEnd::Emphasis));
+            events.push(Event::Text(CowStr::from("\n\n")));
+        }
 
         Ok(())
     }
@@ -342,6 +1170,148 @@ impl std::fmt::Display for DiagramOutputFormat {
         match self {
             DiagramOutputFormat::Svg => write!(f, "svg"),
             DiagramOutputFormat::Png => write!(f, "png"),
+            DiagramOutputFormat::Pdf => write!(f, "pdf"),
+            DiagramOutputFormat::Jpeg => write!(f, "jpeg"),
+            DiagramOutputFormat::Webp => write!(f, "webp"),
+            DiagramOutputFormat::Txt => write!(f, "txt"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiagramOutputFormat {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "png" => Ok(DiagramOutputFormat::Png),
+            "svg" => Ok(DiagramOutputFormat::Svg),
+            "pdf" => Ok(DiagramOutputFormat::Pdf),
+            "jpeg" | "jpg" => Ok(DiagramOutputFormat::Jpeg),
+            "webp" => Ok(DiagramOutputFormat::Webp),
+            "txt" => Ok(DiagramOutputFormat::Txt),
+            _ => Err(eyre!(
+                "Invalid output format: {s}, expected 'png', 'svg', 'pdf', 'jpeg', 'webp' or 'txt'"
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for OnError {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(OnError::Fail),
+            "warn" => Ok(OnError::Warn),
+            "embed" => Ok(OnError::Embed),
+            _ => Err(eyre!(
+                "Invalid on_error: {s}, expected 'fail', 'warn' or 'embed'"
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for Embed {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "inline" => Ok(Embed::Inline),
+            "file" => Ok(Embed::File),
+            "source" => Ok(Embed::Source),
+            _ => Err(eyre!(
+                "Invalid embed: {s}, expected 'inline', 'file' or 'source'"
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for FigureNumbering {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(FigureNumbering::None),
+            "chapter" => Ok(FigureNumbering::Chapter),
+            "book" => Ok(FigureNumbering::Book),
+            _ => Err(eyre!(
+                "Invalid figure_numbering: {s}, expected 'none', 'chapter' or 'book'"
+            )),
+        }
+    }
+}
+
+impl DiagramType {
+    /// Every name and alias `from_name` knows
+    pub const NAMES: &[&str] = &[
+        "actdiag",
+        "blockdiag",
+        "bpmn",
+        "bytefield",
+        "c4plantuml",
+        "c4",
+        "d2",
+        "dbml",
+        "ditaa",
+        "erd",
+        "excalidraw",
+        "graphviz",
+        "dot",
+        "gv",
+        "mermaid",
+        "nomnoml",
+        "nwdiag",
+        "packetdiag",
+        "pikchr",
+        "plantuml",
+        "puml",
+        "rackdiag",
+        "seqdiag",
+        "structurizr",
+        "svgbob",
+        "symbolator",
+        "tikz",
+        "umlet",
+        "vega",
+        "vegalite",
+        "vega-lite",
+        "wavedrom",
+        "wireviz",
+    ];
+
+    /// Looks up a diagram type by its Kroki name or one of its common aliases
+    /// (i.e. `dot` for graphviz), case insensitively
+    pub fn from_name(name: &str) -> DiagramType {
+        match name.to_lowercase().as_str() {
+            "actdiag" => DiagramType::ActDiag,
+            "blockdiag" => DiagramType::BlockDiag,
+            "bpmn" => DiagramType::Bpmn,
+            "bytefield" => DiagramType::Bytefield,
+            "c4plantuml" | "c4" => DiagramType::C4PlantUml,
+            "d2" => DiagramType::D2,
+            "dbml" => DiagramType::Dbml,
+            "ditaa" => DiagramType::Ditaa,
+            "erd" => DiagramType::Erd,
+            "excalidraw" => DiagramType::Excalidraw,
+            "graphviz" | "dot" | "gv" => DiagramType::Graphviz,
+            "mermaid" => DiagramType::Mermaid,
+            "nomnoml" => DiagramType::Nomnoml,
+            "nwdiag" => DiagramType::NwDiag,
+            "packetdiag" => DiagramType::PacketDiag,
+            "pikchr" => DiagramType::Pikchr,
+            "plantuml" | "puml" => DiagramType::PlantUml,
+            "rackdiag" => DiagramType::RackDiag,
+            "seqdiag" => DiagramType::SeqDiag,
+            "structurizr" => DiagramType::Structurizr,
+            "svgbob" => DiagramType::Svgbob,
+            "symbolator" => DiagramType::Symbolator,
+            "tikz" => DiagramType::Tikz,
+            "umlet" => DiagramType::Umlet,
+            "vega" => DiagramType::Vega,
+            "vegalite" | "vega-lite" => DiagramType::VegaLite,
+            "wavedrom" => DiagramType::WaveDrom,
+            "wireviz" => DiagramType::WireViz,
+            other => DiagramType::Other(other.to_string()),
         }
     }
 }
@@ -349,18 +1319,62 @@ impl std::fmt::Display for DiagramOutputFormat {
 impl std::fmt::Display for DiagramType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            DiagramType::ActDiag => write!(f, "actdiag"),
+            DiagramType::BlockDiag => write!(f, "blockdiag"),
+            DiagramType::Bpmn => write!(f, "bpmn"),
+            DiagramType::Bytefield => write!(f, "bytefield"),
+            DiagramType::C4PlantUml => write!(f, "c4plantuml"),
+            DiagramType::D2 => write!(f, "d2"),
+            DiagramType::Dbml => write!(f, "dbml"),
+            DiagramType::Ditaa => write!(f, "ditaa"),
+            DiagramType::Erd => write!(f, "erd"),
+            DiagramType::Excalidraw => write!(f, "excalidraw"),
+            DiagramType::Graphviz => write!(f, "graphviz"),
             DiagramType::Mermaid => write!(f, "mermaid"),
+            DiagramType::Nomnoml => write!(f, "nomnoml"),
+            DiagramType::NwDiag => write!(f, "nwdiag"),
+            DiagramType::PacketDiag => write!(f, "packetdiag"),
+            DiagramType::Pikchr => write!(f, "pikchr"),
             DiagramType::PlantUml => write!(f, "plantuml"),
+            DiagramType::RackDiag => write!(f, "rackdiag"),
+            DiagramType::SeqDiag => write!(f, "seqdiag"),
+            DiagramType::Structurizr => write!(f, "structurizr"),
+            DiagramType::Svgbob => write!(f, "svgbob"),
+            DiagramType::Symbolator => write!(f, "symbolator"),
+            DiagramType::Tikz => write!(f, "tikz"),
+            DiagramType::Umlet => write!(f, "umlet"),
+            DiagramType::Vega => write!(f, "vega"),
+            DiagramType::VegaLite => write!(f, "vegalite"),
+            DiagramType::WaveDrom => write!(f, "wavedrom"),
+            DiagramType::WireViz => write!(f, "wireviz"),
             DiagramType::Other(s) => write!(f, "{}", s.to_lowercase()),
         }
     }
 }
 
 impl DiagramOutputFormat {
-    fn mime_type(&self) -> Mime {
+    pub(crate) fn mime_type(&self) -> Mime {
         match self {
             DiagramOutputFormat::Svg => mime::IMAGE_SVG,
             DiagramOutputFormat::Png => mime::IMAGE_PNG,
+            DiagramOutputFormat::Pdf => mime::APPLICATION_PDF,
+            DiagramOutputFormat::Jpeg => mime::IMAGE_JPEG,
+            DiagramOutputFormat::Webp => "image/webp".parse().expect("valid mime type"),
+            DiagramOutputFormat::Txt => mime::TEXT_PLAIN_UTF_8,
         }
     }
+
+    /// The format that is rendered for `renderer` when this one is configured
+    fn for_renderer(self, renderer: &str) -> DiagramOutputFormat {
+        match (self, renderer) {
+            (DiagramOutputFormat::Pdf, "html") => DiagramOutputFormat::Png,
+            (format, _) => format,
+        }
+    }
+
+    /// Whether the format can be shown as an image, rather than as text or a
+    /// document
+    fn is_image(&self) -> bool {
+        !matches!(self, DiagramOutputFormat::Pdf | DiagramOutputFormat::Txt)
+    }
 }
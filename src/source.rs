@@ -0,0 +1,587 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
+use mdbook::book::Chapter;
+
+use super::{Config, DiagramType, process::continuation_indent};
+
+const DIRECTIVE: &str = "{{#diagram";
+
+/// Replaces every `{{#diagram path/to/file.puml}}` directive in a chapter
+/// with an empty diagram code block that includes its source from that file,
+/// i.e. ```` ```plantuml {src="path/to/file.puml"} ````. An attribute list can
+/// follow the path (`{{#diagram file.mmd {alt="Login"}}}`), and escaped
+/// directives (`\{{#diagram ...}}`) are left for mdbook to unescape. The
+/// code block stays inside the list item or block quote the directive is in.
+pub(crate) fn expand_directives(content: &str, config: &Config) -> Result<String> {
+    if !content.contains(DIRECTIVE) {
+        return Ok(content.to_string());
+    }
+
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(DIRECTIVE) {
+        let escaped = rest[..start].ends_with('\\');
+        let after = &rest[start + DIRECTIVE.len()..];
+        if escaped || !after.starts_with(char::is_whitespace) {
+            out.push_str(&rest[..start + DIRECTIVE.len()]);
+            rest = after;
+            continue;
+        }
+
+        let end = directive_end(after).ok_or_else(|| {
+            let line = rest[start..].lines().next().unwrap_or_default();
+            eyre!("Unterminated diagram directive: {line}")
+        })?;
+        let arguments = after[..end].trim();
+        let (path, attributes) = arguments
+            .split_once(char::is_whitespace)
+            .map(|(path, attributes)| (path, attributes.trim()))
+            .unwrap_or((arguments, ""));
+        let attributes = attributes
+            .strip_prefix('{')
+            .and_then(|a| a.strip_suffix('}'))
+            .map(str::trim)
+            .unwrap_or(attributes);
+
+        let src = path.replace('\\', "\\\\").replace('"', "\\\"");
+        let separator = if attributes.is_empty() { "" } else { ", " };
+        out.push_str(&rest[..start]);
+        let line = &out[out.rfind('\n').map_or(0, |i| i + 1)..];
+        let container = &line[..container_prefix_len(line)];
+        let indent = continuation_indent(container);
+        if container.len() < line.len() {
+            out.push('\n');
+            out.push_str(&indent);
+        }
+        out.push_str(&format!(
+            "```{}{} {{src=\"{src}\"{separator}{attributes}}}\n{indent}```",
+            config.language_prefix,
+            diagram_type_for_path(Path::new(path)),
+        ));
+        rest = &after[end + "}}".len()..];
+        if !rest.is_empty() && !rest.starts_with(['\n', '\r']) {
+            out.push('\n');
+            out.push_str(&indent);
+            rest = rest.trim_start_matches([' ', '\t']);
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The length of the block quote markers and list item markers a line starts
+/// with, i.e. `> 1. ` in `> 1. See {{#diagram flow.dot}}`
+fn container_prefix_len(line: &str) -> usize {
+    let mut rest = line;
+    loop {
+        let trimmed = rest.trim_start_matches([' ', '\t']);
+        let marker = if let Some(after) = trimmed.strip_prefix('>') {
+            Some(after)
+        } else {
+            let digits = trimmed.len()
+                - trimmed
+                    .trim_start_matches(|c: char| c.is_ascii_digit())
+                    .len();
+            let bullet = match digits {
+                0 => trimmed.strip_prefix(['-', '*', '+']),
+                1..=9 => trimmed[digits..].strip_prefix(['.', ')']),
+                _ => None,
+            };
+            bullet.filter(|after| after.is_empty() || after.starts_with([' ', '\t']))
+        };
+        match marker {
+            Some(after) => rest = after,
+            None => return line.len() - trimmed.len(),
+        }
+    }
+}
+
+/// Finds the closing `}}` of a directive, skipping over the closing braces of
+/// an attribute list and anything in quotes
+fn directive_end(input: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') if depth > 0 => depth -= 1,
+            (None, '}') if input[i..].starts_with("}}") => return Some(i),
+            (None, '\n') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Guesses the diagram type of a source file from its extension, which is
+/// usually the diagram type's name (i.e. `.bpmn` files are bpmn diagrams)
+pub(crate) fn diagram_type_for_path(path: &Path) -> DiagramType {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mmd" => DiagramType::Mermaid,
+        "pu" | "iuml" | "wsd" => DiagramType::PlantUml,
+        "bob" => DiagramType::Svgbob,
+        "tex" => DiagramType::Tikz,
+        "uxf" => DiagramType::Umlet,
+        "vl" => DiagramType::VegaLite,
+        "dsl" => DiagramType::Structurizr,
+        other => DiagramType::from_name(other),
+    }
+}
+
+/// The directory containing the chapter's source file, which paths in the
+/// chapter are relative to
+pub(crate) fn chapter_dir(chapter: &Chapter, config: &Config) -> PathBuf {
+    let chapter_dir = chapter
+        .source_path
+        .as_deref()
+        .or(chapter.path.as_deref())
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(""));
+    config.src_dir.join(chapter_dir)
+}
+
+/// Resolves the `src` of a diagram relative to the chapter's source file
+pub(crate) fn resolve(chapter: &Chapter, config: &Config, src: &str) -> PathBuf {
+    chapter_dir(chapter, config).join(src)
+}
+
+/// Reads the source of a diagram included from a file. Files outside of the
+/// book are never read, as they would be sent to Kroki
+pub(crate) fn read(chapter: &Chapter, config: &Config, src: &str) -> Result<String> {
+    let path = resolve(chapter, config, src);
+    let canonical = path
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to read diagram source file {}", path.display()))?;
+    let root = config
+        .root
+        .canonicalize()
+        .unwrap_or_else(|_| config.root.clone());
+    if !canonical.starts_with(&root) {
+        return Err(eyre!(
+            "Diagram source file {} is outside of the book at {}",
+            path.display(),
+            root.display()
+        ));
+    }
+    std::fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read diagram source file {}", path.display()))
+}
+
+/// A title and description for a diagram, taken from its source
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Description {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Pulls the accessible title and description out of a diagram's source:
+/// Mermaid's `accTitle` and `accDescr` (including multi-line
+/// `accDescr { ... }`), and PlantUML's `title` (including multi-line
+/// `title` ... `end title`)
+pub(crate) fn describe(source: &str, diagram_type: &DiagramType) -> Description {
+    let mut description = Description::default();
+    let mut lines = source.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        match diagram_type {
+            DiagramType::Mermaid => {
+                if let Some(title) = mermaid_value(line, "accTitle") {
+                    description.title.get_or_insert(title);
+                } else if let Some(descr) = mermaid_value(line, "accDescr") {
+                    description.description.get_or_insert(descr);
+                } else if let Some(rest) = line.strip_prefix("accDescr")
+                    && rest.trim_start().starts_with('{')
+                {
+                    let rest = rest.trim_start()[1..].trim();
+                    let mut text: Vec<&str> = Vec::new();
+                    if let Some(single_line) = rest.strip_suffix('}') {
+                        text.push(single_line.trim());
+                    } else {
+                        text.push(rest);
+                        for line in lines.by_ref() {
+                            match line.strip_suffix('}') {
+                                Some(last) => {
+                                    text.push(last.trim());
+                                    break;
+                                }
+                                None => text.push(line),
+                            }
+                        }
+                    }
+                    let text = text
+                        .into_iter()
+                        .filter(|line| !line.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    description.description.get_or_insert(text);
+                }
+            }
+            DiagramType::PlantUml => {
+                if line == "title" {
+                    let title = lines
+                        .by_ref()
+                        .take_while(|line| *line != "end title" && *line != "endtitle")
+                        .filter(|line| !line.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    description.title.get_or_insert(title);
+                } else if let Some(title) = line.strip_prefix("title ") {
+                    description.title.get_or_insert(title.trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    description
+}
+
+/// Parses a mermaid `key: value` line
+fn mermaid_value(line: &str, key: &str) -> Option<String> {
+    let value = line.strip_prefix(key)?.trim_start().strip_prefix(':')?;
+    Some(value.trim().to_string())
+}
+
+/// The PlantUML preprocessor directives that pull in other files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Include {
+    /// `!include` and `!include_once`, which include a file once
+    Once,
+    /// `!include_many`, which includes a file every time
+    Many,
+    /// `!includesub file!NAME`, which includes the lines between
+    /// `!startsub NAME` and `!endsub`
+    Sub,
+}
+
+/// Inlines the files PlantUML `!include`s from the book's source tree, since
+/// Kroki can't read them. `dir` is the directory relative paths are resolved
+/// against and `file` is the file the source came from, if any. The standard
+/// library (`!include <C4/C4_Container>`) and URLs are left for PlantUML.
+/// As the included files end up in the source, they are part of its cache key.
+/// Files outside of `root` are never read, as they would be sent to Kroki
+pub(crate) fn inline_plantuml_includes(
+    source: &str,
+    dir: &Path,
+    file: Option<&Path>,
+    root: &Path,
+) -> Result<String> {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let mut stack = Vec::new();
+    if let Some(file) = file {
+        stack.push(file.canonicalize().unwrap_or_else(|_| file.to_path_buf()));
+    }
+    let mut included = HashSet::new();
+    let mut inlined = inline_includes(source, dir, &root, &mut stack, &mut included)?;
+    if !source.ends_with('\n') {
+        inlined.pop();
+    }
+    Ok(inlined)
+}
+
+fn inline_includes(
+    source: &str,
+    dir: &Path,
+    root: &Path,
+    stack: &mut Vec<PathBuf>,
+    included: &mut HashSet<PathBuf>,
+) -> Result<String> {
+    let mut out = String::with_capacity(source.len());
+    for line in source.lines() {
+        let Some((include, argument)) = parse_include(line) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        if argument.starts_with('<') || argument.contains("://") {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+
+        let (path, sub) = match include {
+            Include::Sub => {
+                let (path, sub) = argument
+                    .rsplit_once('!')
+                    .ok_or_else(|| eyre!("Expected `!includesub <file>!<name>`, got: {line}"))?;
+                (path, Some(sub))
+            }
+            Include::Once | Include::Many => (argument, None),
+        };
+        let path = dir.join(path);
+        let canonical = path
+            .canonicalize()
+            .wrap_err_with(|| format!("Failed to resolve PlantUML include {}", path.display()))?;
+        if !canonical.starts_with(root) {
+            return Err(eyre!(
+                "PlantUML include {} is outside of the book at {}",
+                path.display(),
+                root.display()
+            ));
+        }
+
+        if let Some(start) = stack.iter().position(|p| *p == canonical) {
+            let cycle = stack[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(eyre!("PlantUML include cycle: {cycle}"));
+        }
+        if !included.insert(canonical.clone()) && include == Include::Once {
+            continue;
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Failed to read PlantUML include {}", path.display()))?;
+        let contents = match sub {
+            Some(sub) => subpart(&contents, sub)
+                .ok_or_else(|| eyre!("No `!startsub {sub}` found in {}", path.display()))?,
+            None => diagram_body(&contents),
+        };
+
+        stack.push(canonical);
+        let parent = path.parent().unwrap_or(dir);
+        out.push_str(&inline_includes(&contents, parent, root, stack, included)?);
+        stack.pop();
+    }
+    Ok(out)
+}
+
+/// Recognises `!include`, `!include_once`, `!include_many` and `!includesub`
+/// lines, returning the kind of include and its (unquoted) argument
+fn parse_include(line: &str) -> Option<(Include, &str)> {
+    let line = line.trim();
+    let (include, argument) = [
+        ("!include_many", Include::Many),
+        ("!include_once", Include::Once),
+        ("!includesub", Include::Sub),
+        ("!include", Include::Once),
+    ]
+    .into_iter()
+    .find_map(|(directive, include)| {
+        let argument = line.strip_prefix(directive)?;
+        argument
+            .starts_with(char::is_whitespace)
+            .then_some((include, argument.trim()))
+    })?;
+    let argument = argument
+        .strip_prefix('"')
+        .and_then(|a| a.strip_suffix('"'))
+        .unwrap_or(argument);
+    Some((include, argument))
+}
+
+/// Included files may be complete diagrams, in which case only what's between
+/// `@startuml` and `@enduml` is included
+fn diagram_body(contents: &str) -> String {
+    let mut lines = contents.lines();
+    if !contents
+        .lines()
+        .any(|line| line.trim_start().starts_with("@start"))
+    {
+        return contents.to_string();
+    }
+
+    let mut body = String::new();
+    for line in lines.by_ref() {
+        if line.trim_start().starts_with("@start") {
+            break;
+        }
+    }
+    for line in lines.take_while(|line| !line.trim_start().starts_with("@end")) {
+        body.push_str(line);
+        body.push('\n');
+    }
+    body
+}
+
+/// Collects every `!startsub name` ... `!endsub` section of a file
+fn subpart(contents: &str, name: &str) -> Option<String> {
+    let mut out = None;
+    let mut in_sub = false;
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if let Some(sub) = trimmed.strip_prefix("!startsub")
+            && sub.trim() == name
+        {
+            in_sub = true;
+            out.get_or_insert_with(String::new);
+        } else if trimmed == "!endsub" {
+            in_sub = false;
+        } else if in_sub && let Some(out) = out.as_mut() {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expands_directives() {
+        let config = Config::default();
+        let content = "# Title\n\n{{#diagram diagrams/login.puml}}\n\nSee {{#diagram flow.dot {alt=\"A {braced} flow\"}}}\n\n\\{{#diagram escaped.mmd}}\n";
+        assert_eq!(
+            expand_directives(content, &config).unwrap(),
+            "# Title\n\n```plantuml {src=\"diagrams/login.puml\"}\n```\n\nSee \n```graphviz {src=\"flow.dot\", alt=\"A {braced} flow\"}\n```\n\n\\{{#diagram escaped.mmd}}\n"
+        );
+        assert_eq!(
+            expand_directives("- {{#diagram f.dot}}\n- next item\n", &config).unwrap(),
+            "- ```graphviz {src=\"f.dot\"}\n  ```\n- next item\n"
+        );
+        assert_eq!(
+            expand_directives("> 1. See {{#diagram f.dot}} above\n", &config).unwrap(),
+            "> 1. See \n>    ```graphviz {src=\"f.dot\"}\n>    ```\n>    above\n"
+        );
+        assert!(expand_directives("{{#diagram oops.mmd\n}}", &config).is_err());
+    }
+
+    #[test]
+    fn inlines_plantuml_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let styles = dir.path().join("styles");
+        std::fs::create_dir_all(&styles).unwrap();
+        std::fs::write(
+            styles.join("common.iuml"),
+            "@startuml\n!include colors.iuml\nskinparam shadowing false\n@enduml\n",
+        )
+        .unwrap();
+        std::fs::write(
+            styles.join("colors.iuml"),
+            "skinparam backgroundColor #EEE\n",
+        )
+        .unwrap();
+        std::fs::write(
+            styles.join("parts.iuml"),
+            "!startsub ACTORS\nactor Alice\n!endsub\nactor Bob\n",
+        )
+        .unwrap();
+
+        let source = "!include styles/common.iuml\n!include styles/common.iuml\n!include_many styles/colors.iuml\n!includesub styles/parts.iuml!ACTORS\n!include <C4/C4_Container>\nAlice -> Bob\n";
+        assert_eq!(
+            inline_plantuml_includes(source, dir.path(), None, dir.path()).unwrap(),
+            "skinparam backgroundColor #EEE\nskinparam shadowing false\nskinparam backgroundColor #EEE\nactor Alice\n!include <C4/C4_Container>\nAlice -> Bob\n"
+        );
+    }
+
+    #[test]
+    fn rejects_plantuml_include_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.iuml"), "!include b.iuml\n").unwrap();
+        std::fs::write(dir.path().join("b.iuml"), "!include_many a.iuml\n").unwrap();
+
+        let error = inline_plantuml_includes("!include a.iuml\n", dir.path(), None, dir.path())
+            .unwrap_err();
+        assert!(error.to_string().contains("include cycle"), "{error}");
+        assert!(
+            inline_plantuml_includes("!include missing.iuml\n", dir.path(), None, dir.path())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_plantuml_includes_outside_the_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let book = dir.path().join("book");
+        std::fs::create_dir_all(book.join("src")).unwrap();
+        std::fs::write(dir.path().join("secret.iuml"), "password = hunter2\n").unwrap();
+
+        for include in [
+            "../../secret.iuml",
+            &dir.path().join("secret.iuml").display().to_string(),
+        ] {
+            let error = inline_plantuml_includes(
+                &format!("!include {include}\n"),
+                &book.join("src"),
+                None,
+                &book,
+            )
+            .unwrap_err();
+            assert!(
+                error.to_string().contains("is outside of the book"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_diagram_sources_outside_the_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let book = dir.path().join("book");
+        std::fs::create_dir_all(book.join("src")).unwrap();
+        std::fs::write(book.join("src/flow.dot"), "digraph { a -> b }\n").unwrap();
+        std::fs::write(dir.path().join("secret.dot"), "password = hunter2\n").unwrap();
+
+        let config = Config {
+            root: book.clone(),
+            src_dir: book.join("src"),
+            ..Config::default()
+        };
+        let chapter = Chapter::new("Flow", String::new(), "flow.md", Vec::new());
+        assert_eq!(
+            read(&chapter, &config, "flow.dot").unwrap(),
+            "digraph { a -> b }\n"
+        );
+        for src in [
+            "../../secret.dot",
+            &dir.path().join("secret.dot").display().to_string(),
+        ] {
+            let error = read(&chapter, &config, src).unwrap_err();
+            assert!(
+                error.to_string().contains("is outside of the book"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn describes_diagrams() {
+        let mermaid = "flowchart LR\n  accTitle: Login flow\n  accDescr {\n    Alice logs in\n    to the site\n  }\n  A --> B\n";
+        assert_eq!(
+            describe(mermaid, &DiagramType::Mermaid),
+            Description {
+                title: Some("Login flow".to_string()),
+                description: Some("Alice logs in to the site".to_string()),
+            }
+        );
+        assert_eq!(
+            describe("accDescr: A short one", &DiagramType::Mermaid).description,
+            Some("A short one".to_string())
+        );
+
+        let plantuml = "@startuml\ntitle\n  Login\n  flow\nend title\nAlice -> Bob\n@enduml\n";
+        assert_eq!(
+            describe(plantuml, &DiagramType::PlantUml).title,
+            Some("Login flow".to_string())
+        );
+        assert_eq!(
+            describe("title Login flow\nAlice -> Bob", &DiagramType::PlantUml).title,
+            Some("Login flow".to_string())
+        );
+        assert_eq!(
+            describe("title Login flow", &DiagramType::Other("d2".to_string())),
+            Description::default()
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+/// Prefixes every id defined in an SVG with `namespace`, along with every
+/// reference to those ids (`url(#...)`, `href="#..."`, aria references and
+/// `#id` selectors in `<style>` elements). Diagram tools reuse the same ids
+/// (i.e. mermaid's `#my-svg` and `#arrowhead`), so without this the markers
+/// and styles of one inlined diagram apply to every other diagram on the page
+pub(crate) fn namespace_ids(svg: &str, namespace: &str) -> String {
+    let mut ids = HashSet::new();
+    rewrite(
+        svg,
+        &mut |name, value| {
+            if name == "id" {
+                ids.insert(value.to_string());
+            }
+            None
+        },
+        &mut |_| None,
+    );
+    if ids.is_empty() {
+        return svg.to_string();
+    }
+
+    let prefix = |id: &str| match ids.contains(id) {
+        true => format!("{namespace}{id}"),
+        false => id.to_string(),
+    };
+    rewrite(
+        svg,
+        &mut |name, value| match name {
+            "id" => Some(prefix(value)),
+            "href" | "xlink:href" => value.strip_prefix('#').map(|id| format!("#{}", prefix(id))),
+            "aria-labelledby" | "aria-describedby" => Some(
+                value
+                    .split_whitespace()
+                    .map(&prefix)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            _ => Some(rewrite_urls(value, &prefix)),
+        },
+        &mut |css| Some(rewrite_selectors(&rewrite_urls(css, &prefix), &prefix)),
+    )
+}
+
+/// Makes an inline SVG accessible by giving its root element `role="img"` and
+/// an `aria-label`, and adding a `<title>` and `<desc>`. `label` and
+/// `description` must already be escaped. Any existing `role` and aria
+/// references on the root are replaced, as `aria-labelledby` would take
+/// precedence over the label
+pub(crate) fn label(svg: &str, label: &str, description: Option<&str>) -> String {
+    let Some(start) = svg.find("<svg") else {
+        return svg.to_string();
+    };
+    let end = start + tag_end(&svg[start..]);
+    let tag = &svg[start..end];
+    let self_closing = tag.ends_with("/>");
+
+    let mut root = String::from("<svg");
+    let mut rest = &tag["<svg".len()..tag.len() - if self_closing { 2 } else { 1 }];
+    while let Some(attribute) = next_attribute(rest) {
+        let (text, name) = attribute;
+        if !matches!(
+            name,
+            "role" | "aria-label" | "aria-labelledby" | "aria-describedby" | "aria-roledescription"
+        ) {
+            root.push_str(text);
+        }
+        rest = &rest[text.len()..];
+    }
+    root.push_str(rest);
+    root.push_str(&format!(" role=\"img\" aria-label=\"{label}\""));
+
+    let mut children = format!("<title>{label}</title>");
+    if let Some(description) = description {
+        children.push_str(&format!("<desc>{description}</desc>"));
+    }
+    match self_closing {
+        true => format!("{}{root}>{children}</svg>{}", &svg[..start], &svg[end..]),
+        false => format!("{}{root}>{children}{}", &svg[..start], &svg[end..]),
+    }
+}
+
+/// Splits the next attribute (including its leading whitespace) off the
+/// inside of a tag, returning its text and name
+fn next_attribute(input: &str) -> Option<(&str, &str)> {
+    let name_start = input.len() - input.trim_start().len();
+    let name_len = input[name_start..].find(|c: char| c == '=' || c.is_whitespace())?;
+    let name = &input[name_start..name_start + name_len];
+    let after_name = &input[name_start + name_len..];
+    let value = after_name.trim_start().strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value_len = value[1..].find(quote)? + 2;
+    let end = input.len() - value.len() + value_len;
+    Some((&input[..end], name))
+}
+
+/// Walks the tags of an SVG document, replacing attribute values and the
+/// contents of `<style>` elements with whatever `attribute` and `css` return
+/// (leaving them as they were when they return `None`). Everything else is
+/// copied through unchanged
+fn rewrite(
+    svg: &str,
+    attribute: &mut dyn FnMut(&str, &str) -> Option<String>,
+    css: &mut dyn FnMut(&str) -> Option<String>,
+) -> String {
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // comments, CDATA sections, the XML prolog and doctypes are copied as
+        // they are
+        let skip_to = if rest.starts_with("<!--") {
+            Some("-->")
+        } else if rest.starts_with("<![CDATA[") {
+            Some("]]>")
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            Some(">")
+        } else {
+            None
+        };
+        if let Some(terminator) = skip_to {
+            let end = rest
+                .find(terminator)
+                .map_or(rest.len(), |end| end + terminator.len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let end = tag_end(rest);
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        rewrite_tag(tag, attribute, &mut out);
+
+        let is_style = tag.strip_prefix("<style").is_some_and(|after| {
+            after.starts_with(|c: char| c.is_whitespace() || c == '>') && !tag.ends_with("/>")
+        });
+        if is_style {
+            let close = rest.find("</style").unwrap_or(rest.len());
+            let stylesheet = &rest[..close];
+            match css(stylesheet) {
+                Some(stylesheet) => out.push_str(&stylesheet),
+                None => out.push_str(stylesheet),
+            }
+            rest = &rest[close..];
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Returns the length of the tag at the start of `input`, including the
+/// closing `>` and ignoring any `>` in quoted attribute values
+fn tag_end(input: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+/// Copies a single tag into `out`, replacing attribute values as returned by
+/// `attribute`
+fn rewrite_tag(
+    tag: &str,
+    attribute: &mut dyn FnMut(&str, &str) -> Option<String>,
+    out: &mut String,
+) {
+    let mut rest = tag;
+    // everything up to the next quoted value is copied through, and the
+    // attribute name is whatever precedes the `=` before that value
+    while let Some(quote_start) = rest.find(['"', '\'']) {
+        let before = &rest[..quote_start];
+        let quote = rest[quote_start..].chars().next().expect("found a quote");
+        let value_start = quote_start + 1;
+        let Some(value_len) = rest[value_start..].find(quote) else {
+            break;
+        };
+        let value = &rest[value_start..value_start + value_len];
+
+        let name = before
+            .trim_end()
+            .strip_suffix('=')
+            .map(|name| {
+                let name = name.trim_end();
+                let name_start = name.rfind(|c: char| c.is_whitespace()).map_or(0, |i| i + 1);
+                &name[name_start..]
+            })
+            .unwrap_or_default();
+
+        out.push_str(&rest[..value_start]);
+        match attribute(name, value) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(value),
+        }
+        out.push(quote);
+        rest = &rest[value_start + value_len + 1..];
+    }
+    out.push_str(rest);
+}
+
+/// Rewrites the id in every `url(#id)` reference
+fn rewrite_urls(input: &str, prefix: &dyn Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("url(") {
+        let args_start = start + "url(".len();
+        out.push_str(&rest[..args_start]);
+        rest = &rest[args_start..];
+
+        let quote_len = rest.len() - rest.trim_start_matches(['"', '\'']).len();
+        out.push_str(&rest[..quote_len]);
+        rest = &rest[quote_len..];
+
+        if let Some(reference) = rest.strip_prefix('#') {
+            let id_len = reference.find([')', '"', '\'']).unwrap_or(reference.len());
+            out.push('#');
+            out.push_str(&prefix(&reference[..id_len]));
+            rest = &reference[id_len..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Rewrites every `#id` selector in a stylesheet. Ids that aren't defined in
+/// the SVG are left alone, which also keeps hex colors like `#333` intact
+fn rewrite_selectors(css: &str, prefix: &dyn Fn(&str) -> String) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii();
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(hash) = rest.find('#') {
+        let preceded_by_ident = rest[..hash].ends_with(is_ident);
+        out.push_str(&rest[..=hash]);
+        rest = &rest[hash + 1..];
+
+        let id_len = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+        if preceded_by_ident {
+            continue;
+        }
+        out.push_str(&prefix(&rest[..id_len]));
+        rest = &rest[id_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn namespaces_ids_and_references() {
+        let svg = r##"<?xml version="1.0"?><svg id="my-svg" aria-labelledby="chart-title-my-svg" xmlns:xlink="http://www.w3.org/1999/xlink"><title id="chart-title-my-svg">Flow</title><style>#my-svg{fill:#333;}#my-svg .edge{marker-end:url(#arrowhead);}</style><defs><marker id='arrowhead'><path d="M0 0"/></marker></defs><path marker-end="url(#arrowhead)" stroke="#333"/><use xlink:href="#arrowhead" href="#elsewhere"/><text>a > b &amp; #my-svg</text></svg>"##;
+        assert_eq!(
+            namespace_ids(svg, "d123-"),
+            r##"<?xml version="1.0"?><svg id="d123-my-svg" aria-labelledby="d123-chart-title-my-svg" xmlns:xlink="http://www.w3.org/1999/xlink"><title id="d123-chart-title-my-svg">Flow</title><style>#d123-my-svg{fill:#333;}#d123-my-svg .edge{marker-end:url(#d123-arrowhead);}</style><defs><marker id='d123-arrowhead'><path d="M0 0"/></marker></defs><path marker-end="url(#d123-arrowhead)" stroke="#333"/><use xlink:href="#d123-arrowhead" href="#elsewhere"/><text>a > b &amp; #my-svg</text></svg>"##
+        );
+    }
+
+    #[test]
+    fn labels_svg() {
+        let svg = r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" role="graphics-document document" aria-labelledby="chart-title" width="10"><title id="chart-title">x</title></svg>"#;
+        assert_eq!(
+            label(svg, "Login flow", Some("Alice logs in")),
+            r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="10" role="img" aria-label="Login flow"><title>Login flow</title><desc>Alice logs in</desc><title id="chart-title">x</title></svg>"#
+        );
+        assert_eq!(
+            label("<svg/>", "A", None),
+            r#"<svg role="img" aria-label="A"><title>A</title></svg>"#
+        );
+    }
+
+    #[test]
+    fn leaves_svg_without_ids_alone() {
+        let svg = r##"<svg width="10"><!-- id="x" --><rect fill="url(#x)"/></svg>"##;
+        assert_eq!(namespace_ids(svg, "d123-"), svg);
+    }
+}